@@ -0,0 +1,138 @@
+use std::io::Cursor;
+
+use base64::Engine as _;
+use image::{DynamicImage, GrayImage, ImageOutputFormat, Luma, Rgb, RgbImage, imageops};
+
+use crate::provider::{EditMode, ProviderImage};
+
+// Outpainting without an aspect ratio adds this share of the size on every side
+const OUTPAINT_MARGIN: f32 = 0.25;
+
+// Colour of the empty canvas around an image that is outpainted
+const CANVAS_GREY: u8 = 128;
+
+// Decode a base64 upload, with or without a data URL prefix
+pub fn decode_base64_image(data: &str) -> Result<Vec<u8>, String> {
+    let data = match data.split_once(";base64,") {
+        Some((prefix, data)) if prefix.starts_with("data:") => data,
+        _ => data,
+    };
+    base64::engine::general_purpose::STANDARD
+        .decode(data.trim())
+        .map_err(|e| format!("Invalid base64 image: {}", e))
+}
+
+fn parse_aspect_ratio(aspect_ratio: &str) -> Option<(u32, u32)> {
+    let (width, height) = aspect_ratio.split_once(':')?;
+    let width = width.trim().parse().ok().filter(|&width| width > 0)?;
+    let height = height.trim().parse().ok().filter(|&height| height > 0)?;
+    Some((width, height))
+}
+
+fn encode_png(image: DynamicImage) -> Result<ProviderImage, String> {
+    let mut bytes = Cursor::new(Vec::new());
+    image
+        .write_to(&mut bytes, ImageOutputFormat::Png)
+        .map_err(|e| format!("Failed to encode image: {}", e))?;
+    Ok(ProviderImage {
+        bytes: bytes.into_inner(),
+        mime_type: "image/png".to_string(),
+        response: None,
+        safety_attributes: Vec::new(),
+    })
+}
+
+// The canvas size for outpainting: the smallest size with the requested
+// aspect ratio that contains the image, or the image plus a margin
+fn outpaint_size(
+    width: u32,
+    height: u32,
+    aspect_ratio: Option<&str>,
+) -> Result<(u32, u32), String> {
+    let Some(aspect_ratio) = aspect_ratio else {
+        let margin = |size: u32| (size as f32 * OUTPAINT_MARGIN).round() as u32;
+        return Ok((width + 2 * margin(width), height + 2 * margin(height)));
+    };
+
+    let (ratio_width, ratio_height) = parse_aspect_ratio(aspect_ratio).ok_or_else(|| {
+        format!(
+            "Invalid aspect ratio: {}, expected a ratio such as \"16:9\"",
+            aspect_ratio
+        )
+    })?;
+    let (width, height) = (width as u64, height as u64);
+    let (ratio_width, ratio_height) = (ratio_width as u64, ratio_height as u64);
+    let size = if width * ratio_height >= height * ratio_width {
+        (width, (width * ratio_height).div_ceil(ratio_width))
+    } else {
+        ((height * ratio_width).div_ceil(ratio_height), height)
+    };
+    if size == (width, height) {
+        return Err(format!(
+            "The image already has the aspect ratio {}, leave out aspect_ratio to extend it on every side",
+            aspect_ratio
+        ));
+    }
+    Ok((size.0 as u32, size.1 as u32))
+}
+
+// Turn the source image and the optional mask into what providers expect: a
+// mask of the same size as the image that is white where the image may
+// change. For outpainting the canvas is extended first and the mask covers
+// the new area.
+pub fn prepare(
+    image: ProviderImage,
+    mask: Option<Vec<u8>>,
+    mode: EditMode,
+    aspect_ratio: Option<&str>,
+) -> Result<(ProviderImage, Option<ProviderImage>), String> {
+    let decoded = image::load_from_memory(&image.bytes)
+        .map_err(|e| format!("Failed to decode the image: {}", e))?;
+
+    if mode != EditMode::Outpaint {
+        if aspect_ratio.is_some() {
+            return Err("Invalid parameters: aspect_ratio is only used for outpaint".to_string());
+        }
+        let Some(mask) = mask else {
+            return Ok((image, None));
+        };
+        let mask = image::load_from_memory(&mask)
+            .map_err(|e| format!("Failed to decode the mask: {}", e))?;
+        if mask.width() != decoded.width() || mask.height() != decoded.height() {
+            return Err(format!(
+                "Invalid mask: the mask is {}x{} but the image is {}x{}",
+                mask.width(),
+                mask.height(),
+                decoded.width(),
+                decoded.height()
+            ));
+        }
+        let mask = encode_png(DynamicImage::ImageLuma8(mask.to_luma8()))?;
+        return Ok((image, Some(mask)));
+    }
+
+    if mask.is_some() {
+        return Err(
+            "Invalid parameters: outpaint extends the image on its own, do not pass a mask"
+                .to_string(),
+        );
+    }
+    let (width, height) = outpaint_size(decoded.width(), decoded.height(), aspect_ratio)?;
+    let x = (width - decoded.width()) / 2;
+    let y = (height - decoded.height()) / 2;
+
+    let mut canvas = RgbImage::from_pixel(width, height, Rgb([CANVAS_GREY; 3]));
+    imageops::overlay(&mut canvas, &decoded.to_rgb8(), x as i64, y as i64);
+    let mut mask = GrayImage::from_pixel(width, height, Luma([255]));
+    imageops::overlay(
+        &mut mask,
+        &GrayImage::new(decoded.width(), decoded.height()),
+        x as i64,
+        y as i64,
+    );
+
+    Ok((
+        encode_png(DynamicImage::ImageRgb8(canvas))?,
+        Some(encode_png(DynamicImage::ImageLuma8(mask))?),
+    ))
+}
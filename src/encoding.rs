@@ -0,0 +1,175 @@
+use std::io::Cursor;
+
+use image::{DynamicImage, ImageOutputFormat};
+use rmcp::schemars;
+use serde::{Deserialize, Serialize};
+
+use crate::provider::ProviderImage;
+
+// Quality used when none is given, per format
+const DEFAULT_JPEG_QUALITY: u8 = 90;
+const DEFAULT_WEBP_QUALITY: u8 = 85;
+const DEFAULT_AVIF_QUALITY: u8 = 70;
+
+// rav1e speed from 1 (slowest) to 10, higher values trade size for time
+const AVIF_SPEED: u8 = 8;
+
+// A file format images can be saved in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, schemars::JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    Png,
+    #[serde(alias = "jpg")]
+    Jpeg,
+    Webp,
+    Avif,
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "png" => Ok(OutputFormat::Png),
+            "jpeg" | "jpg" => Ok(OutputFormat::Jpeg),
+            "webp" => Ok(OutputFormat::Webp),
+            "avif" => Ok(OutputFormat::Avif),
+            other => Err(format!(
+                "Invalid format: {}, supported values are: png, jpeg, webp, avif",
+                other
+            )),
+        }
+    }
+}
+
+impl OutputFormat {
+    pub fn for_mime_type(mime_type: &str) -> Option<Self> {
+        match mime_type {
+            "image/png" => Some(OutputFormat::Png),
+            "image/jpeg" => Some(OutputFormat::Jpeg),
+            "image/webp" => Some(OutputFormat::Webp),
+            "image/avif" => Some(OutputFormat::Avif),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            OutputFormat::Png => "png",
+            OutputFormat::Jpeg => "jpeg",
+            OutputFormat::Webp => "webp",
+            OutputFormat::Avif => "avif",
+        }
+    }
+
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            OutputFormat::Png => "image/png",
+            OutputFormat::Jpeg => "image/jpeg",
+            OutputFormat::Webp => "image/webp",
+            OutputFormat::Avif => "image/avif",
+        }
+    }
+
+    // Whether the format has a quality setting
+    pub fn is_lossy(self) -> bool {
+        self != OutputFormat::Png
+    }
+}
+
+// Encode an image, `quality` from 1 to 100 is ignored for PNG
+pub fn encode(
+    image: &DynamicImage,
+    format: OutputFormat,
+    quality: Option<u8>,
+) -> Result<Vec<u8>, String> {
+    let mut output = Cursor::new(Vec::new());
+    match format {
+        OutputFormat::Png => image
+            .write_to(&mut output, ImageOutputFormat::Png)
+            .map_err(|e| format!("Failed to encode PNG: {}", e))?,
+        // JPEG has no alpha channel
+        OutputFormat::Jpeg => DynamicImage::ImageRgb8(image.to_rgb8())
+            .write_to(
+                &mut output,
+                ImageOutputFormat::Jpeg(quality.unwrap_or(DEFAULT_JPEG_QUALITY)),
+            )
+            .map_err(|e| format!("Failed to encode JPEG: {}", e))?,
+        // The image crate only writes lossless WebP, which is larger than the PNG
+        OutputFormat::Webp => {
+            let rgba = image.to_rgba8();
+            let encoded = webp::Encoder::from_rgba(&rgba, rgba.width(), rgba.height())
+                .encode(quality.unwrap_or(DEFAULT_WEBP_QUALITY) as f32);
+            return Ok(encoded.to_vec());
+        }
+        OutputFormat::Avif => {
+            let rgba = image.to_rgba8();
+            let pixels = rgba
+                .pixels()
+                .map(|pixel| ravif::RGBA8::new(pixel[0], pixel[1], pixel[2], pixel[3]))
+                .collect::<Vec<_>>();
+            let encoded = ravif::Encoder::new()
+                .with_quality(quality.unwrap_or(DEFAULT_AVIF_QUALITY) as f32)
+                .with_speed(AVIF_SPEED)
+                .encode_rgba(ravif::Img::new(
+                    &pixels[..],
+                    rgba.width() as usize,
+                    rgba.height() as usize,
+                ))
+                .map_err(|e| format!("Failed to encode AVIF: {}", e))?;
+            return Ok(encoded.avif_file);
+        }
+    }
+    Ok(output.into_inner())
+}
+
+// Convert a provider image to the requested format. Images that already have
+// it are returned as they are, unless a quality is asked for.
+pub fn convert(
+    image: ProviderImage,
+    format: OutputFormat,
+    quality: Option<u8>,
+) -> Result<ProviderImage, String> {
+    let requantize = quality.is_some() && format.is_lossy();
+    if image.mime_type == format.mime_type() && !requantize {
+        return Ok(image);
+    }
+    let decoded = image::load_from_memory(&image.bytes)
+        .map_err(|e| format!("Failed to decode the generated image: {}", e))?;
+    Ok(ProviderImage {
+        bytes: encode(&decoded, format, quality)?,
+        mime_type: format.mime_type().to_string(),
+        response: image.response,
+        safety_attributes: image.safety_attributes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn jpeg(quality: u8) -> ProviderImage {
+        let image = DynamicImage::ImageRgb8(image::RgbImage::from_fn(64, 64, |x, y| {
+            image::Rgb([(x * 4) as u8, (y * 4) as u8, ((x ^ y) * 4) as u8])
+        }));
+        ProviderImage {
+            bytes: encode(&image, OutputFormat::Jpeg, Some(quality)).unwrap(),
+            mime_type: "image/jpeg".to_string(),
+            response: None,
+            safety_attributes: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn keeps_images_in_the_requested_format() {
+        let converted = convert(jpeg(95), OutputFormat::Jpeg, None).unwrap();
+        assert_eq!(converted.bytes, jpeg(95).bytes);
+    }
+
+    #[test]
+    fn reencodes_images_in_the_requested_format_with_a_quality() {
+        let converted = convert(jpeg(95), OutputFormat::Jpeg, Some(20)).unwrap();
+        assert_eq!(converted.mime_type, "image/jpeg");
+        assert!(converted.bytes.len() < jpeg(95).bytes.len());
+    }
+}
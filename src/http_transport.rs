@@ -0,0 +1,605 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::channel::mpsc;
+use futures::{SinkExt, StreamExt, future, stream};
+use rmcp::{
+    ServiceExt,
+    model::{
+        ClientJsonRpcMessage, ClientRequest, JsonRpcError, JsonRpcMessage, JsonRpcNotification,
+        JsonRpcRequest, JsonRpcResponse, ProgressToken, RequestId, ServerJsonRpcMessage,
+        ServerNotification,
+    },
+};
+use serde::Deserialize;
+use serde_json::Value;
+use tracing::{debug, error, info, warn};
+use warp::http::StatusCode;
+use warp::hyper::body::Bytes;
+use warp::sse::Event;
+use warp::{Filter, Reply, reply::Response};
+
+use crate::ImageGenerationServer;
+use crate::progress::{self, ProgressTokens};
+
+// Header that carries the session id of the streamable HTTP transport
+const SESSION_ID_HEADER: &str = "mcp-session-id";
+
+// Large enough for images passed inline in tool arguments
+const MAX_MESSAGE_SIZE: u64 = 32 * 1024 * 1024;
+
+// Streamable HTTP sessions without open streams are closed after this long
+// without a message, for clients that never send DELETE
+const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+// How often idle sessions are looked for
+const SESSION_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+// Hosts browser pages may call the MCP endpoints from, besides the one in
+// IMAGE_RESOURCE_SERVER_ADDR
+const LOCAL_HOSTS: &[&str] = &["localhost", "127.0.0.1", "::1"];
+
+// How MCP clients connect to the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportMode {
+    // A single client talking over stdin/stdout
+    Stdio,
+    // Any number of clients using streamable HTTP at /mcp or SSE at /sse
+    Http,
+}
+
+impl std::str::FromStr for TransportMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "stdio" => Ok(TransportMode::Stdio),
+            "http" => Ok(TransportMode::Http),
+            other => Err(format!("{}, supported values are: stdio, http", other)),
+        }
+    }
+}
+
+// Where the messages the server sends in a session go
+#[derive(Default)]
+struct SessionStreams {
+    // The stream opened with GET, receives everything that is not the
+    // response to a pending POST
+    standalone: Option<mpsc::UnboundedSender<ServerJsonRpcMessage>>,
+    // POST requests waiting for their response, by JSON-RPC request id
+    pending: HashMap<RequestId, PendingRequest>,
+}
+
+struct PendingRequest {
+    stream: mpsc::UnboundedSender<ServerJsonRpcMessage>,
+    // The progress token of a tool call, its progress notifications go to
+    // this request's stream
+    progress_token: Option<ProgressToken>,
+}
+
+impl SessionStreams {
+    fn route(&mut self, message: ServerJsonRpcMessage) {
+        let response_id = match &message {
+            JsonRpcMessage::Response(JsonRpcResponse { id, .. })
+            | JsonRpcMessage::Error(JsonRpcError { id, .. }) => Some(id),
+            _ => None,
+        };
+        // Dropping the sender ends the stream of the POST request
+        if let Some(id) = response_id
+            && let Some(request) = self.pending.remove(id)
+        {
+            let _ = request.stream.unbounded_send(message);
+            return;
+        }
+
+        let message = match &self.standalone {
+            Some(stream) => match stream.unbounded_send(message) {
+                Ok(()) => return,
+                Err(e) => {
+                    self.standalone = None;
+                    e.into_inner()
+                }
+            },
+            None => message,
+        };
+
+        // Without a GET stream, progress notifications ride along with the
+        // request they report on. Anything else has nowhere to go.
+        let progress_token = match &message {
+            JsonRpcMessage::Notification(JsonRpcNotification {
+                notification: ServerNotification::ProgressNotification(notification),
+                ..
+            }) => Some(&notification.params.progress_token),
+            _ => None,
+        };
+        self.pending
+            .retain(|_, request| !request.stream.is_closed());
+        let request = progress_token.and_then(|token| {
+            self.pending
+                .values()
+                .find(|request| request.progress_token.as_ref() == Some(token))
+        });
+        match request {
+            Some(request) => {
+                let _ = request.stream.unbounded_send(message);
+            }
+            None => debug!("No open stream for server message, dropping it."),
+        }
+    }
+
+    // Whether the client still listens to the session
+    fn is_open(&mut self) -> bool {
+        if self
+            .standalone
+            .as_ref()
+            .is_some_and(|stream| stream.is_closed())
+        {
+            self.standalone = None;
+        }
+        self.pending
+            .retain(|_, request| !request.stream.is_closed());
+        self.standalone.is_some() || !self.pending.is_empty()
+    }
+}
+
+// An MCP session with its own ImageGenerationServer
+struct Session {
+    // Messages from the client, read by the rmcp service
+    incoming: mpsc::UnboundedSender<ClientJsonRpcMessage>,
+    streams: Arc<Mutex<SessionStreams>>,
+    // Shared with the session's server, see ProgressTokens
+    progress_tokens: ProgressTokens,
+    // When the client last sent a message, for the idle timeout
+    last_active: Mutex<Instant>,
+}
+
+impl Session {
+    fn send(&self, message: ClientJsonRpcMessage) -> bool {
+        *self.last_active.lock().unwrap() = Instant::now();
+        self.incoming.unbounded_send(message).is_ok()
+    }
+
+    // Open a stream for the server messages that answer the request `id`
+    fn expect_response(
+        &self,
+        id: RequestId,
+        progress_token: Option<ProgressToken>,
+    ) -> mpsc::UnboundedReceiver<ServerJsonRpcMessage> {
+        let (stream, receiver) = mpsc::unbounded();
+        self.streams.lock().unwrap().pending.insert(
+            id,
+            PendingRequest {
+                stream,
+                progress_token,
+            },
+        );
+        receiver
+    }
+
+    fn is_idle(&self) -> bool {
+        !self.streams.lock().unwrap().is_open()
+            && self.last_active.lock().unwrap().elapsed() > SESSION_IDLE_TIMEOUT
+    }
+
+    // Open the stream for messages that do not answer a request, replacing
+    // the previous one
+    fn open_standalone(&self) -> mpsc::UnboundedReceiver<ServerJsonRpcMessage> {
+        *self.last_active.lock().unwrap() = Instant::now();
+        let (sender, receiver) = mpsc::unbounded();
+        self.streams.lock().unwrap().standalone = Some(sender);
+        receiver
+    }
+}
+
+#[derive(Clone)]
+struct HttpState {
+    server: ImageGenerationServer,
+    sessions: Arc<Mutex<HashMap<String, Arc<Session>>>>,
+}
+
+impl HttpState {
+    // Start a session backed by a fresh copy of the server state. The session
+    // ends once it is removed from the session map and the client stops
+    // sending messages.
+    fn start_session(&self) -> (String, Arc<Session>) {
+        let session_id = nanoid::nanoid!();
+        let (incoming, incoming_receiver) = mpsc::unbounded::<ClientJsonRpcMessage>();
+        let (outgoing, mut outgoing_receiver) = mpsc::unbounded::<ServerJsonRpcMessage>();
+        let service = self.server.new_session(session_id.clone());
+        let session = Arc::new(Session {
+            incoming,
+            streams: Arc::default(),
+            progress_tokens: service.progress_tokens.clone(),
+            last_active: Mutex::new(Instant::now()),
+        });
+        self.sessions
+            .lock()
+            .unwrap()
+            .insert(session_id.clone(), session.clone());
+        info!(%session_id, "MCP session opened.");
+
+        let streams = session.streams.clone();
+        tokio::spawn(async move {
+            while let Some(message) = outgoing_receiver.next().await {
+                streams.lock().unwrap().route(message);
+            }
+        });
+
+        // rmcp wants transport errors it can build from io errors
+        let outgoing = outgoing.sink_map_err(std::io::Error::other);
+        let sessions = self.sessions.clone();
+        let id = session_id.clone();
+        let closed = service.session_closed.clone();
+        tokio::spawn(async move {
+            match service.serve((outgoing, incoming_receiver)).await {
+                Ok(running) => {
+                    if let Err(e) = running.waiting().await {
+                        error!(session_id = %id, "MCP session failed: {}", e);
+                    }
+                }
+                Err(e) => error!(session_id = %id, "Failed to initialize MCP session: {}", e),
+            }
+            sessions.lock().unwrap().remove(&id);
+            closed.cancel();
+            info!(session_id = %id, "MCP session closed.");
+        });
+
+        (session_id, session)
+    }
+
+    fn session(&self, session_id: &str) -> Option<Arc<Session>> {
+        self.sessions.lock().unwrap().get(session_id).cloned()
+    }
+
+    fn close_session(&self, session_id: &str) -> bool {
+        self.sessions.lock().unwrap().remove(session_id).is_some()
+    }
+
+    // Close the sessions of clients that went away without DELETE. SSE
+    // sessions keep their event stream open and are closed by SessionGuard.
+    async fn close_idle_sessions(self) {
+        let mut interval = tokio::time::interval(SESSION_SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            self.sessions.lock().unwrap().retain(|session_id, session| {
+                let idle = session.is_idle();
+                if idle {
+                    info!(%session_id, "Closing idle MCP session.");
+                }
+                !idle
+            });
+        }
+    }
+}
+
+// Closes an SSE session when its event stream is dropped, i.e. when the
+// client disconnects
+struct SessionGuard {
+    state: HttpState,
+    session_id: String,
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.state.close_session(&self.session_id);
+    }
+}
+
+fn message_event(message: &ServerJsonRpcMessage) -> Option<Event> {
+    match serde_json::to_string(message) {
+        Ok(json) => Some(Event::default().event("message").data(json)),
+        Err(e) => {
+            error!("Failed to serialize MCP message: {}", e);
+            None
+        }
+    }
+}
+
+fn event_stream(
+    messages: mpsc::UnboundedReceiver<ServerJsonRpcMessage>,
+) -> impl futures::Stream<Item = Result<Event, Infallible>> + Send + 'static {
+    messages.filter_map(|message| future::ready(message_event(&message).map(Ok)))
+}
+
+fn status(code: StatusCode, message: &str) -> Response {
+    warp::reply::with_status(message.to_string(), code).into_response()
+}
+
+// The message together with its progress token, which rmcp drops
+type ParsedMessage = (ClientJsonRpcMessage, Option<(RequestId, ProgressToken)>);
+
+fn parse_message(body: &[u8]) -> Result<ParsedMessage, String> {
+    serde_json::from_slice::<Value>(body)
+        .and_then(|raw| {
+            let requested = progress::requested_token(&raw);
+            Ok((serde_json::from_value(raw)?, requested))
+        })
+        .map_err(|e| {
+            warn!("Invalid MCP message: {}", e);
+            format!("Invalid JSON-RPC message: {}", e)
+        })
+}
+
+// GET /sse: open a session of the HTTP+SSE transport. The first event tells
+// the client where to POST its messages.
+fn open_sse_session(state: HttpState) -> Response {
+    let (session_id, session) = state.start_session();
+    let messages = session.open_standalone();
+    let endpoint = Event::default()
+        .event("endpoint")
+        .data(format!("/message?sessionId={}", session_id));
+    let guard = SessionGuard { state, session_id };
+    let events =
+        stream::once(future::ready(Ok(endpoint))).chain(event_stream(messages).map(move |event| {
+            let _ = &guard;
+            event
+        }));
+    warp::sse::reply(warp::sse::keep_alive().stream(events)).into_response()
+}
+
+#[derive(Debug, Deserialize)]
+struct MessageQuery {
+    #[serde(rename = "sessionId")]
+    session_id: String,
+}
+
+// POST /message: a client message for an SSE session, answered on the event
+// stream
+fn post_sse_message(query: MessageQuery, body: Bytes, state: HttpState) -> Response {
+    let Some(session) = state.session(&query.session_id) else {
+        return status(StatusCode::NOT_FOUND, "Unknown session");
+    };
+    let (message, requested) = match parse_message(&body) {
+        Ok(parsed) => parsed,
+        Err(e) => return status(StatusCode::BAD_REQUEST, &e),
+    };
+    if let Some(requested) = requested {
+        session.progress_tokens.insert(requested);
+    }
+    if !session.send(message) {
+        return status(StatusCode::GONE, "Session closed");
+    }
+    StatusCode::ACCEPTED.into_response()
+}
+
+// POST /mcp: a client message of the streamable HTTP transport. Requests are
+// answered with an SSE stream when the client accepts one, or with the plain
+// JSON response otherwise.
+async fn post_mcp_message(
+    session_id: Option<String>,
+    accept: Option<String>,
+    body: Bytes,
+    state: HttpState,
+) -> Result<Response, Infallible> {
+    let (message, requested) = match parse_message(&body) {
+        Ok(parsed) => parsed,
+        Err(e) => return Ok(status(StatusCode::BAD_REQUEST, &e)),
+    };
+
+    let (session_id, session) = match session_id {
+        Some(session_id) => match state.session(&session_id) {
+            Some(session) => (session_id, session),
+            None => return Ok(status(StatusCode::NOT_FOUND, "Unknown session")),
+        },
+        None => match &message {
+            JsonRpcMessage::Request(JsonRpcRequest {
+                request: ClientRequest::InitializeRequest(_),
+                ..
+            }) => state.start_session(),
+            _ => {
+                return Ok(status(
+                    StatusCode::BAD_REQUEST,
+                    "Missing Mcp-Session-Id header, send initialize first",
+                ));
+            }
+        },
+    };
+
+    let progress_token = requested.as_ref().map(|(_, token)| token.clone());
+    if let Some(requested) = requested {
+        session.progress_tokens.insert(requested);
+    }
+    let responses = match &message {
+        JsonRpcMessage::Request(JsonRpcRequest { id, .. }) => {
+            Some(session.expect_response(id.clone(), progress_token))
+        }
+        _ => None,
+    };
+    if !session.send(message) {
+        return Ok(status(StatusCode::GONE, "Session closed"));
+    }
+    let Some(mut responses) = responses else {
+        return Ok(StatusCode::ACCEPTED.into_response());
+    };
+
+    let wants_stream = accept.is_some_and(|accept| accept.contains("text/event-stream"));
+    let reply = if wants_stream {
+        warp::sse::reply(event_stream(responses)).into_response()
+    } else {
+        // Only the response itself fits in a JSON reply
+        loop {
+            match responses.next().await {
+                Some(message @ (JsonRpcMessage::Response(_) | JsonRpcMessage::Error(_))) => {
+                    break warp::reply::json(&message).into_response();
+                }
+                Some(_) => continue,
+                None => return Ok(status(StatusCode::GONE, "Session closed")),
+            }
+        }
+    };
+    Ok(warp::reply::with_header(reply, SESSION_ID_HEADER, session_id).into_response())
+}
+
+// GET /mcp: stream the server messages that do not answer a request
+fn open_mcp_stream(session_id: String, state: HttpState) -> Response {
+    match state.session(&session_id) {
+        Some(session) => {
+            let events = event_stream(session.open_standalone());
+            warp::sse::reply(warp::sse::keep_alive().stream(events)).into_response()
+        }
+        None => status(StatusCode::NOT_FOUND, "Unknown session"),
+    }
+}
+
+// DELETE /mcp: end a session
+fn delete_mcp_session(session_id: String, state: HttpState) -> Response {
+    if state.close_session(&session_id) {
+        StatusCode::NO_CONTENT.into_response()
+    } else {
+        status(StatusCode::NOT_FOUND, "Unknown session")
+    }
+}
+
+// The MCP endpoints: streamable HTTP at /mcp, and the older HTTP+SSE
+// transport at /sse and /message. Every session gets its own copy of the
+// server state, so subscriptions and log levels are not shared.
+// A request whose Origin header names another site
+#[derive(Debug)]
+struct ForbiddenOrigin;
+
+impl warp::reject::Reject for ForbiddenOrigin {}
+
+// Browsers send the Origin of the page with cross-site requests. Without this
+// check any web page could reach the server through a DNS name that resolves
+// to it (DNS rebinding), so only pages served from `host` or localhost are
+// accepted. Clients that are not browsers send no Origin.
+fn is_allowed_origin(origin: &str, host: &str) -> bool {
+    let Some((_, authority)) = origin.split_once("://") else {
+        return false;
+    };
+    let hostname = match authority.strip_prefix('[') {
+        Some(ipv6) => ipv6.split(']').next().unwrap_or_default(),
+        None => authority
+            .rsplit_once(':')
+            .map_or(authority, |(hostname, _)| hostname),
+    };
+    LOCAL_HOSTS
+        .iter()
+        .chain([&host])
+        .any(|allowed| hostname.eq_ignore_ascii_case(allowed.trim_matches(['[', ']'])))
+}
+
+async fn forbidden_origin(rejection: warp::Rejection) -> Result<Response, warp::Rejection> {
+    if rejection.find::<ForbiddenOrigin>().is_some() {
+        return Ok(status(StatusCode::FORBIDDEN, "Origin not allowed"));
+    }
+    Err(rejection)
+}
+
+// `host` is the server address used in image URLs, pages served from it may
+// call the MCP endpoints
+pub fn routes(
+    server: ImageGenerationServer,
+    host: String,
+) -> impl Filter<Extract = (Response,), Error = warp::Rejection> + Clone {
+    let state = HttpState {
+        server,
+        sessions: Arc::default(),
+    };
+    tokio::spawn(state.clone().close_idle_sessions());
+    let state = warp::any().map(move || state.clone());
+    let body = warp::body::content_length_limit(MAX_MESSAGE_SIZE).and(warp::body::bytes());
+    let origin = warp::header::optional::<String>("origin")
+        .and_then(move |origin: Option<String>| {
+            let allowed = origin
+                .as_deref()
+                .is_none_or(|origin| is_allowed_origin(origin, &host));
+            async move {
+                if allowed {
+                    Ok(())
+                } else {
+                    warn!(?origin, "Refused MCP request from another origin.");
+                    Err(warp::reject::custom(ForbiddenOrigin))
+                }
+            }
+        })
+        .untuple_one();
+
+    let sse = warp::path("sse")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(origin.clone())
+        .and(state.clone())
+        .map(open_sse_session);
+    let message = warp::path("message")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(origin.clone())
+        .and(warp::query::<MessageQuery>())
+        .and(body)
+        .and(state.clone())
+        .map(post_sse_message);
+
+    let mcp = warp::path("mcp").and(warp::path::end()).and(origin);
+    let mcp_post = mcp
+        .clone()
+        .and(warp::post())
+        .and(warp::header::optional::<String>(SESSION_ID_HEADER))
+        .and(warp::header::optional::<String>("accept"))
+        .and(body)
+        .and(state.clone())
+        .and_then(post_mcp_message);
+    let mcp_get = mcp
+        .clone()
+        .and(warp::get())
+        .and(warp::header::<String>(SESSION_ID_HEADER))
+        .and(state.clone())
+        .map(open_mcp_stream);
+    let mcp_delete = mcp
+        .and(warp::delete())
+        .and(warp::header::<String>(SESSION_ID_HEADER))
+        .and(state)
+        .map(delete_mcp_session);
+
+    sse.or(message)
+        .unify()
+        .or(mcp_post)
+        .unify()
+        .or(mcp_get)
+        .unify()
+        .or(mcp_delete)
+        .unify()
+        .recover(forbidden_origin)
+        .unify()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allows_localhost_and_the_configured_host() {
+        for origin in [
+            "http://localhost:9981",
+            "http://127.0.0.1",
+            "http://[::1]:9981",
+            "https://LOCALHOST",
+            "https://images.example.com",
+        ] {
+            assert!(
+                is_allowed_origin(origin, "images.example.com"),
+                "{}",
+                origin
+            );
+        }
+    }
+
+    #[test]
+    fn refuses_other_origins() {
+        for origin in [
+            "http://evil.example.com",
+            "http://localhost.evil.example.com",
+            "http://127.0.0.1.nip.io:9981",
+            "null",
+            "",
+        ] {
+            assert!(
+                !is_allowed_origin(origin, "images.example.com"),
+                "{}",
+                origin
+            );
+        }
+    }
+}
@@ -0,0 +1,360 @@
+use std::collections::HashSet;
+use std::path::Path;
+
+use tracing::{error, info, warn};
+
+use crate::metadata::{ImageMetadata, MetadataStore};
+use crate::provider::ProviderError;
+use crate::resources;
+use crate::search;
+use crate::thumbnails;
+
+// Why deleting or renaming a gallery image failed
+#[derive(Debug)]
+pub enum ImageError {
+    NotFound(String),
+    // The name is not a plain filename or does not fit the image
+    Invalid(String),
+    // The new filename is taken, or the image is pinned
+    Conflict(String),
+    Failed(String),
+}
+
+impl std::fmt::Display for ImageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImageError::NotFound(image) => write!(f, "Image not found: {}", image),
+            ImageError::Invalid(message)
+            | ImageError::Conflict(message)
+            | ImageError::Failed(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+// Tools report these like failed generations, see tool_error in main.rs
+impl From<ImageError> for ProviderError {
+    fn from(e: ImageError) -> Self {
+        match e {
+            ImageError::NotFound(_) | ImageError::Invalid(_) | ImageError::Conflict(_) => {
+                ProviderError::InvalidParameter(e.to_string())
+            }
+            ImageError::Failed(message) => ProviderError::Internal(message),
+        }
+    }
+}
+
+// Find a gallery image by id or filename. Anything that could point outside
+// the images directory is refused before looking.
+async fn find(
+    resources_path: &Path,
+    metadata: &MetadataStore,
+    image: &str,
+) -> Result<ImageMetadata, ImageError> {
+    if !resources::is_safe_filename(image) {
+        return Err(ImageError::Invalid(format!("Invalid filename: {}", image)));
+    }
+    search::gallery_metadata(metadata, &resources_path.join("images"))
+        .await
+        .map_err(|e| ImageError::Failed(format!("Failed to list images: {}", e)))?
+        .into_iter()
+        .find(|entry| entry.id == image || entry.filename == image)
+        .ok_or_else(|| ImageError::NotFound(image.to_string()))
+}
+
+// Delete an image file and its cached variants. A file that is already gone
+// is not an error.
+pub async fn remove_file(resources_path: &Path, filename: &str) -> std::io::Result<()> {
+    match tokio::fs::remove_file(resources_path.join("images").join(filename)).await {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    if let Err(e) = thumbnails::remove_cached_variants(resources_path, filename).await {
+        warn!(%filename, "Failed to remove cached variants: {}", e);
+    }
+    Ok(())
+}
+
+// Delete a gallery image by id or filename, together with its metadata.
+// Pinned images have to be unpinned first. The index entry goes first and is
+// put back when the file cannot be deleted, so the two never disagree.
+pub async fn delete(
+    resources_path: &Path,
+    metadata: &MetadataStore,
+    image: &str,
+) -> Result<ImageMetadata, ImageError> {
+    let entry = find(resources_path, metadata, image).await?;
+    if entry.pinned {
+        return Err(ImageError::Conflict(format!(
+            "Image {} is pinned, unpin it first",
+            entry.filename
+        )));
+    }
+    metadata
+        .remove(&HashSet::from([entry.filename.clone()]))
+        .await
+        .map_err(|e| {
+            ImageError::Failed(format!(
+                "Failed to remove image from the metadata index: {}",
+                e
+            ))
+        })?;
+    if let Err(e) = remove_file(resources_path, &entry.filename).await {
+        restore_entry(metadata, &entry).await;
+        return Err(ImageError::Failed(format!("Failed to delete image: {}", e)));
+    }
+    info!(filename = %entry.filename, "Deleted image.");
+    Ok(entry)
+}
+
+// Rename a gallery image by id or filename. The extension is kept when the
+// new name has none, and it cannot be changed since it gives the MIME type.
+// Returns the old filename and the updated metadata.
+pub async fn rename(
+    resources_path: &Path,
+    metadata: &MetadataStore,
+    image: &str,
+    new_filename: &str,
+) -> Result<(String, ImageMetadata), ImageError> {
+    let mut entry = find(resources_path, metadata, image).await?;
+    let new_filename = new_filename.trim();
+    if !resources::is_url_safe_filename(new_filename) || new_filename.starts_with('.') {
+        return Err(ImageError::Invalid(format!(
+            "Invalid filename: {}, only letters, digits, '.', '_' and '-' are allowed",
+            new_filename
+        )));
+    }
+    let extension = Path::new(&entry.filename)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default();
+    let new_filename = match Path::new(new_filename)
+        .extension()
+        .and_then(|extension| extension.to_str())
+    {
+        None if !extension.is_empty() => format!("{}.{}", new_filename, extension),
+        Some(new_extension)
+            if resources::mime_type_for(new_filename)
+                != resources::mime_type_for(&entry.filename) =>
+        {
+            return Err(ImageError::Invalid(format!(
+                "Invalid filename: {}, the extension must stay .{} instead of .{}",
+                new_filename, extension, new_extension
+            )));
+        }
+        _ => new_filename.to_string(),
+    };
+    if new_filename == entry.filename {
+        return Ok((new_filename, entry));
+    }
+
+    let images_dir = resources_path.join("images");
+    let old_path = images_dir.join(&entry.filename);
+    let new_path = images_dir.join(&new_filename);
+    let linked = move_file(&old_path, &new_path, &new_filename).await?;
+
+    // Images without an index entry are known by their filename
+    let old_entry = entry.clone();
+    let old_filename = std::mem::replace(&mut entry.filename, new_filename);
+    if entry.id == old_filename {
+        entry.id = entry.filename.clone();
+    }
+
+    // The index keeps the latest entry of every id, so the renamed entry
+    // replaces the old one as soon as it is appended. Any failure from here
+    // on gives the image its old name back.
+    let renamed = async {
+        metadata
+            .append(std::slice::from_ref(&entry))
+            .await
+            .map_err(|e| format!("Failed to update the metadata index: {}", e))?;
+        if let Err(e) = metadata
+            .remove(&HashSet::from([old_filename.clone()]))
+            .await
+        {
+            restore_entry(metadata, &old_entry).await;
+            return Err(format!("Failed to update the metadata index: {}", e));
+        }
+        // Also drops the cached variants when the file was moved
+        if let Err(e) = remove_file(resources_path, &old_filename).await {
+            restore_entry(metadata, &old_entry).await;
+            return Err(format!("Failed to rename image: {}", e));
+        }
+        Ok(())
+    };
+    if let Err(e) = renamed.await {
+        let undone = if linked {
+            tokio::fs::remove_file(&new_path).await
+        } else {
+            tokio::fs::rename(&new_path, &old_path).await
+        };
+        if let Err(e) = undone {
+            error!(from = %entry.filename, to = %old_filename, "Failed to undo the rename of an image: {}", e);
+        }
+        return Err(ImageError::Failed(e));
+    }
+    info!(from = %old_filename, to = %entry.filename, "Renamed image.");
+    Ok((old_filename, entry))
+}
+
+// Give a gallery image a new name without ever replacing an existing file.
+// Returns whether the file was hard linked, in which case the old name still
+// has to be removed.
+async fn move_file(
+    old_path: &Path,
+    new_path: &Path,
+    new_filename: &str,
+) -> Result<bool, ImageError> {
+    let taken = || ImageError::Conflict(format!("An image named {} already exists", new_filename));
+    match tokio::fs::hard_link(old_path, new_path).await {
+        Ok(()) => return Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => return Err(taken()),
+        // Some filesystems, e.g. FAT or some network shares, have no hard
+        // links. Fall back to a rename after checking the name is free.
+        Err(e) => warn!("Failed to hard link image, renaming it instead: {}", e),
+    }
+    match tokio::fs::try_exists(new_path).await {
+        Ok(false) => {}
+        Ok(true) => return Err(taken()),
+        Err(e) => return Err(ImageError::Failed(format!("Failed to rename image: {}", e))),
+    }
+    tokio::fs::rename(old_path, new_path)
+        .await
+        .map_err(|e| ImageError::Failed(format!("Failed to rename image: {}", e)))?;
+    Ok(false)
+}
+
+// Put an entry back into the index after a failed change of its image
+async fn restore_entry(metadata: &MetadataStore, entry: &ImageMetadata) {
+    if let Err(e) = metadata.append(std::slice::from_ref(entry)).await {
+        error!(filename = %entry.filename, "Failed to restore the metadata of an image: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A gallery in a fresh temporary directory
+    struct Gallery {
+        path: std::path::PathBuf,
+        metadata: MetadataStore,
+    }
+
+    impl Gallery {
+        async fn new() -> Self {
+            let path = std::env::temp_dir().join(format!("imagen3-mcp-test-{}", nanoid::nanoid!()));
+            tokio::fs::create_dir_all(path.join("images"))
+                .await
+                .unwrap();
+            let metadata = MetadataStore::new(&path);
+            Self { path, metadata }
+        }
+
+        async fn add(&self, id: &str, filename: &str, pinned: bool) {
+            tokio::fs::write(self.path.join("images").join(filename), b"image")
+                .await
+                .unwrap();
+            let entry = ImageMetadata {
+                id: id.to_string(),
+                filename: filename.to_string(),
+                mime_type: resources::mime_type_for(filename).to_string(),
+                pinned,
+                ..Default::default()
+            };
+            self.metadata.append(&[entry]).await.unwrap();
+        }
+
+        fn exists(&self, filename: &str) -> bool {
+            self.path.join("images").join(filename).exists()
+        }
+    }
+
+    impl Drop for Gallery {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.path);
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_paths_outside_the_gallery() {
+        let gallery = Gallery::new().await;
+        gallery.add("a", "a.png", false).await;
+        tokio::fs::write(gallery.path.join("secret.png"), b"secret")
+            .await
+            .unwrap();
+
+        for image in ["../secret.png", "..", "images/a.png", "/etc/passwd"] {
+            let deleted = delete(&gallery.path, &gallery.metadata, image).await;
+            assert!(matches!(deleted, Err(ImageError::Invalid(_))), "{}", image);
+        }
+        for new_filename in ["../secret.png", "sub/b.png", ".hidden", "b c.png"] {
+            let renamed = rename(&gallery.path, &gallery.metadata, "a", new_filename).await;
+            assert!(
+                matches!(renamed, Err(ImageError::Invalid(_))),
+                "{}",
+                new_filename
+            );
+        }
+        assert!(gallery.path.join("secret.png").exists());
+        assert!(gallery.exists("a.png"));
+    }
+
+    #[tokio::test]
+    async fn rename_keeps_the_extension() {
+        let gallery = Gallery::new().await;
+        gallery.add("a", "a.png", false).await;
+
+        let (old_filename, entry) = rename(&gallery.path, &gallery.metadata, "a", "red-fox")
+            .await
+            .unwrap();
+        assert_eq!(old_filename, "a.png");
+        assert_eq!(entry.filename, "red-fox.png");
+        assert_eq!(entry.id, "a");
+        assert!(!gallery.exists("a.png"));
+        assert!(gallery.exists("red-fox.png"));
+        let indexed = gallery.metadata.get("a").await.unwrap().unwrap();
+        assert_eq!(indexed.filename, "red-fox.png");
+        assert_eq!(gallery.metadata.all().await.unwrap().len(), 1);
+
+        let renamed = rename(&gallery.path, &gallery.metadata, "a", "red-fox.jpg").await;
+        assert!(matches!(renamed, Err(ImageError::Invalid(_))));
+        let (_, entry) = rename(&gallery.path, &gallery.metadata, "a", "fox.PNG")
+            .await
+            .unwrap();
+        assert_eq!(entry.filename, "fox.PNG");
+    }
+
+    #[tokio::test]
+    async fn rename_never_replaces_an_image() {
+        let gallery = Gallery::new().await;
+        gallery.add("a", "a.png", false).await;
+        gallery.add("b", "b.png", false).await;
+
+        let renamed = rename(&gallery.path, &gallery.metadata, "a", "b").await;
+        assert!(matches!(renamed, Err(ImageError::Conflict(_))));
+        assert!(gallery.exists("a.png"));
+        assert!(gallery.exists("b.png"));
+    }
+
+    #[tokio::test]
+    async fn delete_refuses_pinned_images() {
+        let gallery = Gallery::new().await;
+        gallery.add("a", "a.png", true).await;
+        gallery.add("b", "b.png", false).await;
+
+        let deleted = delete(&gallery.path, &gallery.metadata, "a").await;
+        assert!(matches!(deleted, Err(ImageError::Conflict(_))));
+        assert!(gallery.exists("a.png"));
+
+        let entry = delete(&gallery.path, &gallery.metadata, "b.png")
+            .await
+            .unwrap();
+        assert_eq!(entry.id, "b");
+        assert!(!gallery.exists("b.png"));
+        assert!(gallery.metadata.get("b").await.unwrap().is_none());
+
+        let deleted = delete(&gallery.path, &gallery.metadata, "b").await;
+        assert!(matches!(deleted, Err(ImageError::NotFound(_))));
+    }
+}
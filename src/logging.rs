@@ -0,0 +1,171 @@
+use std::fmt;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use rmcp::{
+    Peer, RoleServer,
+    model::{LoggingLevel, LoggingMessageNotificationParam},
+};
+use serde_json::{Map, Value};
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+use tracing::{Event, Level, Subscriber, field::Field};
+use tracing_subscriber::{Layer, layer::Context};
+
+// Log records kept for sessions that fall behind before old ones are dropped
+const LOG_CHANNEL_CAPACITY: usize = 256;
+
+// Level used until the client sends logging/setLevel
+pub const DEFAULT_CLIENT_LOG_LEVEL: LoggingLevel = LoggingLevel::Warning;
+
+// A log event from this crate, ready to be sent as notifications/message
+#[derive(Debug, Clone)]
+pub struct LogRecord {
+    pub level: LoggingLevel,
+    pub logger: String,
+    pub data: Value,
+    // The session whose tool call emitted the record
+    pub session: String,
+}
+
+tokio::task_local! {
+    static SESSION: String;
+}
+
+// Run a tool call with its log records tagged with the session, so that
+// they are only forwarded to that session's client
+pub async fn session_scope<F: Future>(session_id: String, future: F) -> F::Output {
+    SESSION.scope(session_id, future).await
+}
+
+// Tracing layer that publishes the events of this crate's tool calls to the
+// MCP client that made the call. Events from dependencies are skipped, in
+// particular rmcp logs every message it sends, which would otherwise loop back
+// forever. Events outside a tool call belong to the server as a whole, e.g.
+// the retention sweep or other sessions' transports, and only go to the log.
+pub struct McpLogLayer {
+    sender: broadcast::Sender<LogRecord>,
+}
+
+impl McpLogLayer {
+    pub fn new() -> (Self, broadcast::Sender<LogRecord>) {
+        let (sender, _) = broadcast::channel(LOG_CHANNEL_CAPACITY);
+        (
+            Self {
+                sender: sender.clone(),
+            },
+            sender,
+        )
+    }
+}
+
+impl<S: Subscriber> Layer<S> for McpLogLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        if !metadata.target().starts_with(env!("CARGO_CRATE_NAME")) {
+            return;
+        }
+        // Nobody is listening before a client connects
+        if self.sender.receiver_count() == 0 {
+            return;
+        }
+        let Ok(session) = SESSION.try_with(Clone::clone) else {
+            return;
+        };
+
+        let mut visitor = JsonVisitor(Map::new());
+        event.record(&mut visitor);
+        let _ = self.sender.send(LogRecord {
+            level: logging_level(metadata.level()),
+            logger: metadata.target().to_string(),
+            data: Value::Object(visitor.0),
+            session,
+        });
+    }
+}
+
+// Collects the fields of an event into a JSON object
+struct JsonVisitor(Map<String, Value>);
+
+impl tracing::field::Visit for JsonVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), format!("{:?}", value).into());
+    }
+}
+
+fn logging_level(level: &Level) -> LoggingLevel {
+    match *level {
+        Level::ERROR => LoggingLevel::Error,
+        Level::WARN => LoggingLevel::Warning,
+        Level::INFO => LoggingLevel::Info,
+        _ => LoggingLevel::Debug,
+    }
+}
+
+fn severity(level: &LoggingLevel) -> u8 {
+    match level {
+        LoggingLevel::Debug => 0,
+        LoggingLevel::Info => 1,
+        LoggingLevel::Notice => 2,
+        LoggingLevel::Warning => 3,
+        LoggingLevel::Error => 4,
+        LoggingLevel::Critical => 5,
+        LoggingLevel::Alert => 6,
+        LoggingLevel::Emergency => 7,
+    }
+}
+
+// Send log records at or above the session's level to the client until the
+// session closes. Records of other sessions are skipped, they carry their
+// prompts, arguments and provider errors.
+pub async fn forward_logs(
+    peer: Peer<RoleServer>,
+    session_id: String,
+    mut records: broadcast::Receiver<LogRecord>,
+    level: Arc<Mutex<LoggingLevel>>,
+    closed: CancellationToken,
+) {
+    loop {
+        let received = tokio::select! {
+            received = records.recv() => received,
+            _ = closed.cancelled() => break,
+        };
+        let record = match received {
+            Ok(record) => record,
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+        if record.session != session_id {
+            continue;
+        }
+        if severity(&record.level) < severity(&level.lock().unwrap()) {
+            continue;
+        }
+        let notification = LoggingMessageNotificationParam {
+            level: record.level,
+            logger: Some(record.logger),
+            data: record.data,
+        };
+        if peer.notify_logging_message(notification).await.is_err() {
+            // The transport is gone, so is the session
+            break;
+        }
+    }
+}
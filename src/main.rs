@@ -1,27 +1,435 @@
-use base64::{self, Engine as _};
-use chrono;
+use base64::Engine as _;
 use directories::ProjectDirs;
-use nanoid;
-use reqwest;
+use image::GenericImageView;
+use image::imageops::FilterType;
 use rmcp::{
-    ServerHandler, ServiceExt,
-    model::{Implementation, ServerCapabilities, ServerInfo},
-    schemars, tool,
+    Peer, RoleServer, ServerHandler, ServiceExt,
+    handler::server::tool::ToolCallContext,
+    model::{
+        CallToolRequestParam, CallToolResult, Content, GetPromptRequestParam, GetPromptResult,
+        Implementation, ListPromptsResult, ListResourceTemplatesResult, ListResourcesResult,
+        ListToolsResult, LoggingLevel, PaginatedRequestParam, ReadResourceRequestParam,
+        ReadResourceResult, ServerCapabilities, ServerInfo, SetLevelRequestParam,
+        SubscribeRequestParam, UnsubscribeRequestParam,
+    },
+    schemars,
+    service::RequestContext,
+    tool,
 };
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::env;
-use std::fs;
+use std::io::Cursor;
 use std::net::SocketAddr;
-use std::path::PathBuf;
-use tracing::{error, info, instrument};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, instrument, warn};
 use tracing_subscriber::{EnvFilter, fmt, prelude::*};
-use warp::Filter;
+use warp::{Filter, Reply};
+
+mod editing;
+mod encoding;
+mod http_transport;
+mod images;
+mod logging;
+mod metadata;
+mod progress;
+mod prompts;
+mod provenance;
+mod provider;
+mod resources;
+mod retention;
+mod search;
+mod stdio_transport;
+mod thumbnails;
+
+use encoding::OutputFormat;
+use http_transport::TransportMode;
+use images::ImageError;
+use logging::{DEFAULT_CLIENT_LOG_LEVEL, LogRecord, McpLogLayer};
+use metadata::{ImageMetadata, MetadataStore};
+use progress::{Progress, ProgressTokens, Stage};
+use prompts::PromptCatalogue;
+use provider::{
+    EditMode, EditRequest, ErrorDetails, GenerationRequest, ImageProvider, ModelInfo,
+    ProviderError, ProviderImage, ProviderRegistry, RetryPolicy, UpscaleFactor,
+};
+use resources::Subscribers;
+use retention::RetentionPolicy;
+use search::{ListQuery, SearchQuery};
+use thumbnails::{THUMBNAIL_DIMENSION, VariantError, VariantQuery};
 
 #[derive(Debug, Clone)]
 struct ImageGenerationServer {
+    providers: ProviderRegistry,
+    prompts: PromptCatalogue,
     resources_path: PathBuf,
     image_resource_server_addr: String,
     server_port: u16,
+    result_mode: ResultMode,
+    inline_max_dimension: Option<u32>,
+    // How failed provider calls are repeated
+    retry_policy: RetryPolicy,
+    // How every image in the gallery was made
+    metadata: MetadataStore,
+    // Identifies the MCP session in the log records of its tool calls
+    session_id: String,
+    // The connected client, set once the MCP session is initialized
+    peer: Option<Peer<RoleServer>>,
+    // Resource URIs the client subscribed to
+    subscriptions: Arc<Mutex<HashSet<String>>>,
+    // The peers and subscriptions of all sessions, shared between them
+    subscribers: Subscribers,
+    // Log records published by the tracing layer, forwarded to the client
+    log_records: broadcast::Sender<LogRecord>,
+    // Minimum level of the log records sent to the client
+    log_level: Arc<Mutex<LoggingLevel>>,
+    // Progress tokens of the tool calls of this session, see progress.rs
+    progress_tokens: ProgressTokens,
+    // Cancelled when the session ends, stops the tasks serving it
+    session_closed: CancellationToken,
+}
+
+// The gallery page served at the root of the HTTP server
+const GALLERY_HTML: &str = include_str!("gallery.html");
+
+// Number of resources returned per resources/list page
+const RESOURCES_PAGE_SIZE: usize = 100;
+
+impl ImageGenerationServer {
+    // A copy for a new MCP session. The configuration is shared, the client
+    // connection, subscriptions and log level are not.
+    fn new_session(&self, session_id: String) -> Self {
+        Self {
+            session_id,
+            peer: None,
+            subscriptions: Arc::default(),
+            log_level: Arc::new(Mutex::new(DEFAULT_CLIENT_LOG_LEVEL)),
+            progress_tokens: ProgressTokens::default(),
+            session_closed: CancellationToken::new(),
+            ..self.clone()
+        }
+    }
+
+    // Where the HTTP server can be reached, for image URLs
+    fn image_base_url(&self) -> String {
+        format!(
+            "http://{}:{}",
+            self.image_resource_server_addr, self.server_port
+        )
+    }
+
+    // A text entry per image, followed by the image itself when inline results
+    // are enabled. `details` is added to every text entry.
+    fn image_results(&self, images: &[GeneratedImage], details: &str) -> Vec<Content> {
+        let total = images.len();
+        let mut contents = Vec::new();
+        for (index, image) in images.iter().enumerate() {
+            let mut entry = format!("Image {} of {}", index + 1, total);
+            if self.result_mode.includes_url() {
+                // Use the configured address and port
+                entry.push_str(&format!(
+                    "\nURL: {}/images/{}",
+                    self.image_base_url(),
+                    image.filename
+                ));
+            }
+            entry.push_str(&format!(
+                "\nFilename: {}\nMIME type: {}\n{}",
+                image.filename, image.mime_type, details
+            ));
+            let safety_attributes = &image.metadata.safety_attributes;
+            if !safety_attributes.is_empty() {
+                let scores = safety_attributes
+                    .iter()
+                    .map(|attribute| format!("{} {:.2}", attribute.category, attribute.score))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                entry.push_str(&format!("\nSafety attributes: {}", scores));
+            }
+            contents.push(Content::text(entry));
+
+            if self.result_mode.includes_inline() {
+                match inline_image_content(
+                    &image.bytes,
+                    &image.mime_type,
+                    self.inline_max_dimension,
+                ) {
+                    Ok(content) => contents.push(content),
+                    Err(e) => {
+                        error!(filename = %image.filename, "Failed to encode inline image: {}", e);
+                        contents.push(Content::text(format!(
+                            "Failed to encode image {} inline: {}",
+                            image.filename, e
+                        )));
+                    }
+                }
+            }
+        }
+        contents
+    }
+
+    // Load a gallery image by filename, or decode a base64 upload
+    async fn load_image(
+        &self,
+        filename: Option<&str>,
+        image: Option<&str>,
+    ) -> Result<ProviderImage, ProviderError> {
+        let invalid = |message: String| ProviderError::InvalidParameter(message);
+        match (filename, image) {
+            (Some(filename), None) => {
+                if !resources::is_safe_filename(filename) {
+                    return Err(invalid(format!("Invalid filename: {}", filename)));
+                }
+                let path = self.resources_path.join("images").join(filename);
+                let bytes = tokio::fs::read(&path).await.map_err(|e| {
+                    if e.kind() == std::io::ErrorKind::NotFound {
+                        invalid(format!("Image not found: {}", filename))
+                    } else {
+                        error!(file_path = %path.display(), "Failed to read image: {}", e);
+                        e.into()
+                    }
+                })?;
+                Ok(ProviderImage {
+                    bytes,
+                    mime_type: resources::mime_type_for(filename).to_string(),
+                    response: None,
+                    safety_attributes: Vec::new(),
+                })
+            }
+            (None, Some(data)) => {
+                let bytes = editing::decode_base64_image(data).map_err(invalid)?;
+                let format = image::guess_format(&bytes)
+                    .map_err(|e| invalid(format!("Unsupported image: {}", e)))?;
+                Ok(ProviderImage {
+                    bytes,
+                    mime_type: format.to_mime_type().to_string(),
+                    response: None,
+                    safety_attributes: Vec::new(),
+                })
+            }
+            (Some(_), Some(_)) => Err(invalid(
+                "Invalid parameters: pass either filename or image, not both".to_string(),
+            )),
+            (None, None) => Err(invalid(
+                "Invalid parameters: pass the filename of a gallery image or a base64 image"
+                    .to_string(),
+            )),
+        }
+    }
+
+    // Validate the arguments of edit_image, load the source image and pick the provider
+    async fn edit_request(
+        &self,
+        args: &EditImageArgs,
+    ) -> Result<(Arc<dyn ImageProvider>, EditRequest), ProviderError> {
+        let (provider, model) = self
+            .providers
+            .resolve(args.provider.as_deref(), args.model.as_deref())?;
+        if !model.capabilities.editing {
+            return Err(ProviderError::InvalidParameter(format!(
+                "Model {} does not support editing, use list_models to find a model that does",
+                model.id
+            )));
+        }
+
+        let image = self
+            .load_image(args.filename.as_deref(), args.image.as_deref())
+            .await?;
+        // Everything below only fails because of the arguments
+        let prepared = async {
+            let mask = args
+                .mask
+                .as_deref()
+                .map(editing::decode_base64_image)
+                .transpose()?;
+
+            let sample_count = check_number_of_images(args.number_of_images, &model)?;
+            let negative_prompt = check_negative_prompt(args.negative_prompt.as_deref(), &model)?;
+            check_seed(args.seed, &model)?;
+            let (image, mask) =
+                editing::prepare(image, mask, args.mode, args.aspect_ratio.as_deref())?;
+            Ok::<_, String>((image, mask, sample_count, negative_prompt))
+        };
+        let (image, mask, sample_count, negative_prompt) =
+            prepared.await.map_err(ProviderError::InvalidParameter)?;
+
+        let request = EditRequest {
+            model: model.id,
+            prompt: args.prompt.clone(),
+            mode: args.mode,
+            image,
+            mask,
+            sample_count,
+            negative_prompt,
+            seed: args.seed,
+        };
+        Ok((provider, request))
+    }
+
+    // Upscale a gallery image with a high quality local resize. None of the
+    // configured providers offers an upscaler on the APIs this server uses.
+    async fn upscale(&self, args: &UpscaleImageArgs) -> Result<ProviderImage, ProviderError> {
+        if !resources::is_safe_filename(&args.filename) {
+            return Err(ProviderError::InvalidParameter(format!(
+                "Invalid filename: {}",
+                args.filename
+            )));
+        }
+        let path = self.resources_path.join("images").join(&args.filename);
+        let bytes = match tokio::fs::read(&path).await {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(ProviderError::InvalidParameter(format!(
+                    "Image not found: {}",
+                    args.filename
+                )));
+            }
+            Err(e) => {
+                error!(file_path = %path.display(), "Failed to read image: {}", e);
+                return Err(e.into());
+            }
+        };
+
+        let (width, height) = image::load_from_memory(&bytes)?.dimensions();
+        let multiplier = args.factor.multiplier();
+        if width.max(height) * multiplier > MAX_UPSCALED_DIMENSION {
+            return Err(ProviderError::InvalidParameter(format!(
+                "Invalid upscale factor: the image is {}x{}, {} would exceed the maximum of {} pixels per side",
+                width,
+                height,
+                args.factor.as_str(),
+                MAX_UPSCALED_DIMENSION
+            )));
+        }
+
+        Progress::current().report(Stage::Queued).await;
+        // Lanczos on large images takes a while, keep it off the async workers
+        let factor = args.factor;
+        Ok(tokio::task::spawn_blocking(move || upscale_locally(&bytes, factor)).await??)
+    }
+
+    // A failed tool call as an MCP tool error. The JSON carries the error code
+    // so that clients can tell a prompt to rephrase from a request to repeat
+    // later.
+    fn tool_error(&self, context: &str, e: &ProviderError) -> CallToolResult {
+        error_result(context, e, e.details(&self.retry_policy))
+    }
+
+    // A tool error after the provider was called `attempts` times
+    fn retried_tool_error(
+        &self,
+        context: &str,
+        e: &ProviderError,
+        attempts: u32,
+    ) -> CallToolResult {
+        let mut details = e.details(&self.retry_policy);
+        details.attempts = Some(attempts);
+        error_result(context, e, details)
+    }
+
+    // Add the saved images to the metadata index
+    async fn record_metadata(&self, images: &[GeneratedImage]) {
+        let entries = images
+            .iter()
+            .map(|image| image.metadata.clone())
+            .collect::<Vec<_>>();
+        if let Err(e) = self.metadata.append(&entries).await {
+            error!("Failed to record image metadata: {}", e);
+        }
+    }
+
+    // Pin or unpin a gallery image by id or filename. Images without an index
+    // entry get one.
+    async fn set_pinned(&self, image: &str, pinned: bool) -> Result<String, ImageError> {
+        let images_dir = self.resources_path.join("images");
+        let entries = search::gallery_metadata(&self.metadata, &images_dir)
+            .await
+            .map_err(|e| ImageError::Failed(format!("Failed to list images: {}", e)))?;
+        let Some(mut entry) = entries
+            .into_iter()
+            .find(|entry| entry.id == image || entry.filename == image)
+        else {
+            return Err(ImageError::NotFound(image.to_string()));
+        };
+        if entry.pinned == pinned {
+            return Ok(format!(
+                "Image {} is already {}",
+                entry.filename,
+                if pinned { "pinned" } else { "unpinned" }
+            ));
+        }
+        entry.pinned = pinned;
+        self.metadata
+            .append(std::slice::from_ref(&entry))
+            .await
+            .map_err(|e| ImageError::Failed(format!("Failed to record image metadata: {}", e)))?;
+        info!(filename = %entry.filename, pinned, "Image pin changed.");
+        if pinned {
+            Ok(format!(
+                "Pinned image {}, the garbage collection keeps it",
+                entry.filename
+            ))
+        } else {
+            Ok(format!(
+                "Unpinned image {}, the garbage collection may remove it",
+                entry.filename
+            ))
+        }
+    }
+
+    // Tell the clients that new images were saved to the gallery
+    async fn notify_images_saved(&self, images: &[GeneratedImage]) {
+        let filenames = images
+            .iter()
+            .map(|image| image.filename.as_str())
+            .collect::<Vec<_>>();
+        self.notify_gallery_changed(&filenames).await;
+    }
+
+    // Tell the clients of all sessions that images were added, removed or
+    // renamed
+    async fn notify_gallery_changed(&self, filenames: &[&str]) {
+        self.subscribers.notify_gallery_changed(filenames).await;
+    }
+}
+
+// How generated images are handed back to the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ResultMode {
+    // Only base64 image content, for clients that cannot reach the HTTP server
+    Inline,
+    // Only the URL served by the HTTP server
+    Url,
+    Both,
+}
+
+impl ResultMode {
+    fn includes_inline(self) -> bool {
+        self != ResultMode::Url
+    }
+
+    fn includes_url(self) -> bool {
+        self != ResultMode::Inline
+    }
+}
+
+impl std::str::FromStr for ResultMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "inline" => Ok(ResultMode::Inline),
+            "url" => Ok(ResultMode::Url),
+            "both" => Ok(ResultMode::Both),
+            other => Err(format!(
+                "{}, supported values are: inline, url, both",
+                other
+            )),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, schemars::JsonSchema)]
@@ -31,366 +439,1169 @@ struct ImagePrompt {
     )]
     prompt: String,
 
-    // Supported values are "1:1", "3:4", "4:3", "9:16", and "16:9". The default is "1:1".
+    // Supported values depend on the model, see list_models. The default is "1:1".
     #[schemars(
-        description = "The aspect ratio of the image to generate. Supported values are \"1:1\", \"3:4\", \"4:3\", \"9:16\", and \"16:9\". The default is \"1:1\"."
+        description = "The aspect ratio of the image to generate. \"1:1\", \"3:4\", \"4:3\", \"9:16\", and \"16:9\" are supported by most models, use list_models to see the values each model supports. The default is \"1:1\"."
     )]
     aspect_ratio: Option<String>,
-}
 
-// Request and response structures for the Gemini API
-#[derive(Debug, Serialize)]
-struct GeminiRequest {
-    instances: Vec<GeminiInstance>,
-    parameters: GeminiParameters,
+    #[schemars(
+        description = "The number of images to generate, up to 4 depending on the model. The default is 1. Use more than one when the user wants to choose between several candidates."
+    )]
+    number_of_images: Option<u32>,
+
+    #[schemars(
+        description = "A description of what to discourage in the generated images, e.g. \"blurry, text, watermark\". The negative prompt MUST be in English. Only supported by some models, see list_models."
+    )]
+    negative_prompt: Option<String>,
+
+    #[schemars(
+        description = "A random seed from 1 to 2147483647. The same prompt, parameters and seed reproduce the same image. Only supported by some models, see list_models. Setting a seed disables the invisible watermark, and it cannot be combined with enhance_prompt."
+    )]
+    seed: Option<u32>,
+
+    #[schemars(
+        description = "Whether the model may rewrite the prompt to add detail before generating. The default is the model's own default. Must not be true when a seed is set. Only supported by some models, see list_models."
+    )]
+    enhance_prompt: Option<bool>,
+
+    #[schemars(
+        description = "How strictly the images follow the prompt, from 1 to 30. Higher values follow the prompt more closely at the cost of variety. Only supported by some models, see list_models."
+    )]
+    guidance_scale: Option<f32>,
+
+    #[schemars(
+        description = "The image provider to use: \"imagen\", \"gemini\", \"openai\" or \"mock\". The default is the provider configured on the server. Only set this when the user explicitly asks for a provider."
+    )]
+    provider: Option<String>,
+
+    #[schemars(
+        description = "The model to use, either a full model id or an alias such as \"imagen-4\". The default is the default model of the provider. Use list_models to see the available models."
+    )]
+    model: Option<String>,
+
+    #[schemars(
+        description = "Labels to file the images under, e.g. the project they belong to. Use them to find the images again with search_images."
+    )]
+    tags: Option<Vec<String>>,
+
+    #[schemars(
+        description = "The file format of the images: \"png\", \"jpeg\", \"webp\" or \"avif\". The default is the format of the provider, usually PNG. Use webp or jpeg with a quality for small files, e.g. for the web."
+    )]
+    output_format: Option<OutputFormat>,
+
+    #[schemars(
+        description = "The compression quality from 1 to 100 for jpeg, webp and avif, lower values give smaller files. The default depends on the format."
+    )]
+    quality: Option<u8>,
 }
 
-#[derive(Debug, Serialize)]
-struct GeminiInstance {
-    prompt: String,
+// Imagen takes seeds in the positive int32 range.
+const MAX_SEED: u32 = i32::MAX as u32;
+
+const MIN_GUIDANCE_SCALE: f32 = 1.0;
+const MAX_GUIDANCE_SCALE: f32 = 30.0;
+
+fn check_number_of_images(number_of_images: Option<u32>, model: &ModelInfo) -> Result<u32, String> {
+    let number_of_images = number_of_images.unwrap_or(1);
+    let max_sample_count = model.capabilities.max_sample_count;
+    if !(1..=max_sample_count).contains(&number_of_images) {
+        return Err(format!(
+            "Invalid number of images: {}, supported values for {} are 1 to {}",
+            number_of_images, model.id, max_sample_count
+        ));
+    }
+    Ok(number_of_images)
 }
 
-#[derive(Debug, Serialize)]
-struct GeminiParameters {
-    #[serde(rename = "sampleCount")]
-    sample_count: i32,
-    #[serde(rename = "aspectRatio")]
-    aspect_ratio: Option<String>,
+fn check_negative_prompt(
+    negative_prompt: Option<&str>,
+    model: &ModelInfo,
+) -> Result<Option<String>, String> {
+    match negative_prompt.map(str::trim) {
+        Some("") => Err("Invalid negative prompt: must not be empty".to_string()),
+        Some(_) if !model.capabilities.negative_prompt => Err(format!(
+            "Model {} does not support negative prompts, describe what the image should contain in the prompt instead",
+            model.id
+        )),
+        other => Ok(other.map(str::to_string)),
+    }
 }
 
-#[derive(Debug, Deserialize)]
-struct GeminiResponse {
-    predictions: Option<Vec<GeminiPrediction>>,
+// Trim the tags given by the client and drop empty and repeated ones
+fn normalize_tags(tags: Option<&[String]>) -> Vec<String> {
+    let mut normalized: Vec<String> = Vec::new();
+    for tag in tags.unwrap_or_default() {
+        let tag = tag.trim();
+        if !tag.is_empty() && !normalized.iter().any(|t| t.eq_ignore_ascii_case(tag)) {
+            normalized.push(tag.to_string());
+        }
+    }
+    normalized
 }
 
-#[derive(Debug, Deserialize)]
-struct GeminiPrediction {
-    #[serde(rename = "mimeType")]
-    mime_type: String,
-    #[serde(rename = "bytesBase64Encoded")]
-    bytes_base64_encoded: String,
-}
-
-// Function to generate an image using the Gemini API
-#[instrument(skip(resources_path), fields(prompt_length = prompt.len()))]
-async fn generate_image_from_gemini(
-    prompt: &str,
-    aspect_ratio: Option<&str>,
-    resources_path: &PathBuf,
-) -> Result<Vec<String>, Box<dyn std::error::Error>> {
-    info!(?prompt, ?aspect_ratio, "Generating image from Gemini");
-
-    // Get the API key from environment variables
-    let api_key =
-        env::var("GEMINI_API_KEY").map_err(|_| "GEMINI_API_KEY environment variable not set")?;
-
-    // Create the request
-    let request = GeminiRequest {
-        instances: vec![GeminiInstance {
-            prompt: prompt.to_string(),
-        }],
-        parameters: GeminiParameters {
-            sample_count: 1, // Just generate one image
-            aspect_ratio: aspect_ratio.map(|s| s.to_string()),
-        },
+// A seed the model would ignore is refused, or the seed recorded with the
+// images would not reproduce them
+fn check_seed(seed: Option<u32>, model: &ModelInfo) -> Result<(), String> {
+    let Some(seed) = seed else {
+        return Ok(());
     };
-    info!(
-        "Sending request to Gemini: {}",
-        serde_json::to_string(&request)?
-    );
-
-    // Create URL with API key
-    let base_url = env::var("BASE_URL")
-        .unwrap_or_else(|_| "https://generativelanguage.googleapis.com".to_string());
-    let url = format!(
-        "{}/v1beta/models/imagen-3.0-generate-002:predict?key={}",
-        base_url, api_key
-    );
+    if !model.capabilities.seed {
+        return Err(format!(
+            "Model {} does not support seeds, use list_models to find a model that does",
+            model.id
+        ));
+    }
+    if !(1..=MAX_SEED).contains(&seed) {
+        return Err(format!(
+            "Invalid seed: {}, supported values are 1 to {}",
+            seed, MAX_SEED
+        ));
+    }
+    Ok(())
+}
 
-    // Make the request
-    let client = reqwest::Client::new();
-    let response_result = client.post(&url).json(&request).send().await;
+impl ImagePrompt {
+    // Validate the arguments against the model and turn them into a provider request
+    fn to_request(&self, model: &ModelInfo) -> Result<GenerationRequest, String> {
+        let capabilities = &model.capabilities;
+        if let Some(aspect_ratio) = &self.aspect_ratio
+            && !capabilities.aspect_ratios.contains(&aspect_ratio.as_str())
+        {
+            return Err(format!(
+                "Invalid aspect ratio: {}, supported values for {} are: {}",
+                aspect_ratio,
+                model.id,
+                capabilities.aspect_ratios.join(", ")
+            ));
+        }
 
-    let response_text = match response_result {
-        Ok(resp) => resp.text().await?,
-        Err(e) => {
-            error!("Failed to send request to Gemini: {}", e);
-            return Err(e.into());
+        let number_of_images = check_number_of_images(self.number_of_images, model)?;
+        let negative_prompt = check_negative_prompt(self.negative_prompt.as_deref(), model)?;
+        check_seed(self.seed, model)?;
+        if self.enhance_prompt.is_some() && !capabilities.enhance_prompt {
+            return Err(format!(
+                "Model {} does not support enhance_prompt, leave it out",
+                model.id
+            ));
         }
-    };
 
-    let response: GeminiResponse = match serde_json::from_str(&response_text) {
-        Ok(response) => response,
-        Err(e) => {
-            error!(
-                response_body = %response_text,
-                "Failed to parse Gemini response: {}",
-                e
+        // Prompt rewriting happens before sampling, so a rewritten prompt would
+        // make the seed meaningless.
+        if self.seed.is_some() && self.enhance_prompt == Some(true) {
+            return Err(
+                "Invalid parameters: enhance_prompt cannot be true when a seed is set".to_string(),
             );
+        }
+
+        if let Some(quality) = self.quality {
+            if !self.output_format.is_some_and(OutputFormat::is_lossy) {
+                return Err(
+                    "Invalid parameters: quality is only used with output_format jpeg, webp or avif"
+                        .to_string(),
+                );
+            }
+            if !(1..=100).contains(&quality) {
+                return Err(format!(
+                    "Invalid quality: {}, supported values are 1 to 100",
+                    quality
+                ));
+            }
+        }
+
+        if self.guidance_scale.is_some() && !capabilities.guidance_scale {
             return Err(format!(
-                "Failed to parse Gemini response: {}
-The response was: {}",
-                e, response_text
-            )
-            .into());
+                "Model {} does not support guidance_scale, leave it out",
+                model.id
+            ));
         }
+        if let Some(guidance_scale) = self.guidance_scale
+            && !(MIN_GUIDANCE_SCALE..=MAX_GUIDANCE_SCALE).contains(&guidance_scale)
+        {
+            return Err(format!(
+                "Invalid guidance scale: {}, supported values are {} to {}",
+                guidance_scale, MIN_GUIDANCE_SCALE, MAX_GUIDANCE_SCALE
+            ));
+        }
+
+        Ok(GenerationRequest {
+            model: model.id.clone(),
+            prompt: self.prompt.clone(),
+            sample_count: number_of_images,
+            aspect_ratio: self.aspect_ratio.clone(),
+            negative_prompt,
+            seed: self.seed,
+            // Imagen rejects seeds while the invisible watermark is enabled
+            add_watermark: self.seed.map(|_| false),
+            enhance_prompt: match self.seed {
+                Some(_) => Some(false),
+                None => self.enhance_prompt,
+            },
+            guidance_scale: self.guidance_scale,
+            output_format: self.output_format,
+            quality: self.quality,
+        })
+    }
+}
+
+#[derive(Deserialize, schemars::JsonSchema)]
+struct EditImageArgs {
+    #[schemars(
+        description = "The filename of the gallery image to edit, as returned by generate_image. Either filename or image must be set."
+    )]
+    filename: Option<String>,
+
+    #[schemars(
+        description = "A base64 encoded image to edit instead of a gallery image, optionally as a data URL."
+    )]
+    image: Option<String>,
+
+    #[schemars(
+        description = "What to add, remove or change, or the new background. The prompt MUST be in English."
+    )]
+    prompt: String,
+
+    #[schemars(
+        description = "\"inpaint-insert\" adds what the prompt describes, \"inpaint-remove\" removes it, \"outpaint\" extends the image beyond its borders and \"background-swap\" keeps the subject and replaces the background."
+    )]
+    mode: EditMode,
+
+    #[schemars(
+        description = "An optional base64 encoded mask with the size of the image, white where the image may change and black elsewhere. Not used for outpaint."
+    )]
+    mask: Option<String>,
+
+    #[schemars(
+        description = "Only for outpaint: the aspect ratio of the extended image, e.g. \"16:9\". Without it the image is extended by a quarter on every side."
+    )]
+    aspect_ratio: Option<String>,
+
+    #[schemars(
+        description = "The number of edited versions to generate, up to 4 depending on the model. The default is 1."
+    )]
+    number_of_images: Option<u32>,
+
+    #[schemars(
+        description = "A description of what to discourage in the edited images. Only supported by some models, see list_models."
+    )]
+    negative_prompt: Option<String>,
+
+    #[schemars(
+        description = "A random seed from 1 to 2147483647. Only supported by some models, see list_models."
+    )]
+    seed: Option<u32>,
+
+    #[schemars(
+        description = "The image provider to use. The default is the provider configured on the server. Only providers with models that support editing can be used, see list_models."
+    )]
+    provider: Option<String>,
+
+    #[schemars(
+        description = "The model to use, either a full model id or an alias. The default is the default model of the provider."
+    )]
+    model: Option<String>,
+
+    #[schemars(description = "Labels to file the edited images under, for search_images.")]
+    tags: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+struct UpscaleImageArgs {
+    #[schemars(
+        description = "The filename of the gallery image to upscale, as returned by generate_image."
+    )]
+    filename: String,
+
+    #[schemars(description = "How much to enlarge the image: \"x2\" or \"x4\".")]
+    factor: UpscaleFactor,
+}
+
+#[derive(Deserialize, schemars::JsonSchema)]
+struct ReadImageMetadataArgs {
+    #[schemars(
+        description = "The filename of a gallery image. Either filename or image must be set."
+    )]
+    filename: Option<String>,
+
+    #[schemars(
+        description = "A base64 encoded PNG, JPEG or WebP file, optionally as a data URL, e.g. an image that was copied out of the gallery."
+    )]
+    image: Option<String>,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+struct RenameImageArgs {
+    #[schemars(description = "The filename or id of the gallery image")]
+    image: String,
+
+    #[schemars(
+        description = "The new filename, made of letters, digits, '.', '_' and '-', e.g. \"red-fox_portrait\". The extension is added when it is left out and cannot be changed."
+    )]
+    new_filename: String,
+}
+
+// Upscaled images larger than this on either side are refused
+const MAX_UPSCALED_DIMENSION: u32 = 8192;
+
+// An image that was saved to the resources directory
+#[derive(Debug)]
+struct GeneratedImage {
+    filename: String,
+    mime_type: String,
+    bytes: Vec<u8>,
+    metadata: ImageMetadata,
+}
+
+// Encode an image as MCP image content, shrinking it first when it is larger
+// than `max_dimension` on either side.
+fn inline_image_content(
+    bytes: &[u8],
+    mime_type: &str,
+    max_dimension: Option<u32>,
+) -> Result<Content, image::ImageError> {
+    let engine = base64::engine::general_purpose::STANDARD;
+    // AVIF cannot be decoded here, so it is never shrunk
+    let Some(max_dimension) = max_dimension.filter(|_| mime_type != "image/avif") else {
+        return Ok(Content::image(engine.encode(bytes), mime_type));
     };
 
-    let predictions = response.predictions.unwrap_or_default();
+    let decoded = image::load_from_memory(bytes)?;
+    if decoded.width() <= max_dimension && decoded.height() <= max_dimension {
+        return Ok(Content::image(engine.encode(bytes), mime_type));
+    }
 
-    // Make sure we got at least one prediction
-    if predictions.is_empty() {
-        error!("No images were generated by Gemini. This might be due to safety filters.");
-        return Err("No images were generated. This might be due to the image not passing Google's safety review.".into());
+    let resized = decoded.resize(max_dimension, max_dimension, FilterType::Lanczos3);
+    let mut bytes = Cursor::new(Vec::new());
+    resized.write_to(&mut bytes, image::ImageOutputFormat::Png)?;
+    Ok(Content::image(
+        engine.encode(bytes.into_inner()),
+        "image/png",
+    ))
+}
+
+fn error_result(context: &str, e: &ProviderError, details: ErrorDetails) -> CallToolResult {
+    error!(code = e.code(), "{}: {}", context, e);
+    let json = serde_json::to_string_pretty(&serde_json::json!({ "error": details }))
+        .unwrap_or_else(|_| format!("{}: {}", context, e));
+    CallToolResult::error(vec![Content::text(json)])
+}
+
+// Mention retried provider calls in the tool result
+fn push_attempts(details: &mut String, attempts: u32) {
+    if attempts > 1 {
+        details.push_str(&format!("\nAttempts: {}", attempts));
     }
+}
 
-    let mut filenames = Vec::new();
+// Tell the client about samples the safety filters removed, when some of
+// the requested images are missing
+fn filtered_note(filtered_count: u32, reasons: &[String]) -> Option<String> {
+    if filtered_count == 0 {
+        return None;
+    }
+    let mut note = format!(
+        "{} image(s) were blocked by the safety filters and are missing from the results.",
+        filtered_count
+    );
+    if !reasons.is_empty() {
+        note.push_str(&format!("\nReasons: {}", reasons.join("; ")));
+    }
+    Some(note)
+}
+
+// Convert generated images to the requested output format and quality where
+// the provider could not produce them
+async fn convert_images(
+    images: Vec<ProviderImage>,
+    provider: &dyn ImageProvider,
+    request: &GenerationRequest,
+) -> Result<Vec<ProviderImage>, ProviderError> {
+    let Some(format) = request.output_format else {
+        return Ok(images);
+    };
+    if provider.encodes_output(request) {
+        return Ok(images);
+    }
+    let quality = request.quality;
+    // Encoding WebP and especially AVIF takes a while
+    let images = tokio::task::spawn_blocking(move || {
+        images
+            .into_iter()
+            .map(|image| encoding::convert(image, format, quality))
+            .collect::<Result<Vec<_>, _>>()
+    })
+    .await?
+    .map_err(ProviderError::Decode)?;
+    Ok(images)
+}
 
-    // Get the first prediction
-    for pred in predictions {
-        // Generate a filename based on the prompt
-        let timestamp = chrono::Local::now().format("%Y%m%d%H%M%S").to_string();
+// Enlarge an image with a Lanczos filter
+fn upscale_locally(
+    bytes: &[u8],
+    factor: UpscaleFactor,
+) -> Result<ProviderImage, image::ImageError> {
+    let decoded = image::load_from_memory(bytes)?;
+    let multiplier = factor.multiplier();
+    let resized = decoded.resize_exact(
+        decoded.width() * multiplier,
+        decoded.height() * multiplier,
+        FilterType::Lanczos3,
+    );
+    let mut bytes = Cursor::new(Vec::new());
+    resized.write_to(&mut bytes, image::ImageOutputFormat::Png)?;
+    Ok(ProviderImage {
+        bytes: bytes.into_inner(),
+        mime_type: "image/png".to_string(),
+        response: None,
+        safety_attributes: Vec::new(),
+    })
+}
+
+// Write the images returned by a provider to the images directory, with
+// their provenance embedded in the file. `details` holds what all images have
+// in common, the fields of each image are filled in here. Either all images
+// are saved or none: when a write fails, the files written so far are
+// removed again.
+#[instrument(skip(images, resources_path, details), fields(num_images = images.len()))]
+async fn save_images(
+    images: Vec<ProviderImage>,
+    resources_path: &Path,
+    details: &ImageMetadata,
+) -> Result<Vec<GeneratedImage>, ProviderError> {
+    let mut saved: Vec<GeneratedImage> = Vec::new();
+
+    for image in images {
+        // Generate a unique filename
+        let now = chrono::Local::now();
+        let timestamp = now.format("%Y%m%d%H%M%S").to_string();
         let id = nanoid::nanoid!(10);
-        let filename = format!("{}_{}.png", id, timestamp);
+        let filename = format!(
+            "{}_{}.{}",
+            id,
+            timestamp,
+            resources::extension_for(&image.mime_type)
+        );
         let path = resources_path.join("images").join(&filename);
 
-        // Decode the base64 image using updated API
-        let image_data =
-            match base64::engine::general_purpose::STANDARD.decode(&pred.bytes_base64_encoded) {
-                Ok(data) => data,
-                Err(e) => {
-                    error!("Failed to decode base64 image: {}", e);
-                    return Err(e.into());
-                }
-            };
+        let metadata = ImageMetadata {
+            id,
+            filename: filename.clone(),
+            mime_type: image.mime_type.clone(),
+            created_at: now.to_rfc3339(),
+            provider_response: image.response,
+            safety_attributes: image.safety_attributes,
+            ..details.clone()
+        };
+        // An image that cannot carry its provenance is still worth keeping
+        let bytes = match provenance::embed(&image.bytes, &image.mime_type, &metadata) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                warn!(%filename, "Failed to embed image metadata: {}", e);
+                image.bytes
+            }
+        };
 
         // Write the image to disk
-        if let Err(e) = fs::write(&path, &image_data) {
+        if let Err(e) = tokio::fs::write(&path, &bytes).await {
             error!(file_path = %path.display(), "Failed to write image to disk: {}", e);
+            let written = saved.iter().map(|image| &image.filename);
+            for filename in written.chain([&filename]) {
+                if let Err(e) = images::remove_file(resources_path, filename).await {
+                    warn!(%filename, "Failed to remove partially saved image: {}", e);
+                }
+            }
             return Err(e.into());
         }
         info!(file_path = %path.display(), "Successfully saved generated image.");
 
-        filenames.push(filename);
+        saved.push(GeneratedImage {
+            filename,
+            mime_type: image.mime_type,
+            bytes,
+            metadata,
+        });
     }
 
-    Ok(filenames)
+    Ok(saved)
 }
 
 // Define the tool and its implementation
 #[tool(tool_box)]
 impl ImageGenerationServer {
     #[tool(
-        description = "Generate an image based on a prompt. Returns an image URL that can be used in markdown format like ![description](URL) to display the image"
+        description = "Generate one or more images based on a prompt. Returns the images and, depending on the server configuration, an image URL for each image that can be used in markdown format like ![description](URL) to display the image"
     )]
     // #[instrument(skip(self))] // Removed due to macro conflict
-    async fn generate_image(&self, #[tool(aggr)] args: ImagePrompt) -> String {
+    async fn generate_image(
+        &self,
+        #[tool(aggr)] args: ImagePrompt,
+        ct: CancellationToken,
+    ) -> Result<CallToolResult, rmcp::Error> {
         info!(?args, "Received image generation request"); // Log args explicitly
 
-        // Generate the image using the Gemini API
+        // Validate the arguments before anything is sent to the API
+        let resolved = self
+            .providers
+            .resolve(args.provider.as_deref(), args.model.as_deref())
+            .and_then(|(provider, model)| {
+                let request = args
+                    .to_request(&model)
+                    .map_err(ProviderError::InvalidParameter)?;
+                Ok((provider, request))
+            });
+        let (provider, request) = match resolved {
+            Ok(resolved) => resolved,
+            Err(e) => return Ok(self.tool_error("Invalid image generation request", &e)),
+        };
+        let progress = Progress::current();
+        progress.report(Stage::Queued).await;
 
-        const SUPPORTED_ASPECT_RATIOS: [&str; 5] = ["1:1", "3:4", "4:3", "9:16", "16:9"];
-        if let Some(aspect_ratio) = &args.aspect_ratio {
-            if !SUPPORTED_ASPECT_RATIOS.contains(&aspect_ratio.as_str()) {
-                let error_msg = format!(
-                    "Invalid aspect ratio: {}, supported values are: {}",
-                    aspect_ratio,
-                    SUPPORTED_ASPECT_RATIOS.join(", ")
+        // Generate the images with the selected provider and save them
+        let details = ImageMetadata {
+            operation: "generate".to_string(),
+            prompt: Some(request.prompt.clone()),
+            negative_prompt: request.negative_prompt.clone(),
+            aspect_ratio: request.aspect_ratio.clone(),
+            seed: request.seed,
+            guidance_scale: request.guidance_scale,
+            enhance_prompt: request.enhance_prompt,
+            tags: normalize_tags(args.tags.as_deref()),
+            provider: provider.name().to_string(),
+            model: request.model.clone(),
+            ..Default::default()
+        };
+        progress.report(Stage::Sending).await;
+        let (output, attempts) = self
+            .retry_policy
+            .run(&ct, || provider.generate(&request))
+            .await;
+        let result = match output {
+            Ok(output) => {
+                progress.report(Stage::Decoding).await;
+                match convert_images(output.images, provider.as_ref(), &request).await {
+                    Ok(images) => save_images(images, &self.resources_path, &details)
+                        .await
+                        .map(|images| (images, output.filtered_count, output.filtered_reasons)),
+                    Err(e) => Err(e),
+                }
+            }
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok((images, filtered_count, filtered_reasons)) => {
+                progress.report(Stage::Saved).await;
+                self.record_metadata(&images).await;
+                self.notify_images_saved(&images).await;
+
+                let mut details =
+                    format!("Provider: {}\nModel: {}", provider.name(), request.model);
+                if let Some(seed) = request.seed {
+                    details.push_str(&format!("\nSeed: {}", seed));
+                }
+                push_attempts(&mut details, attempts);
+                let mut contents = self.image_results(&images, &details);
+                if let Some(note) = filtered_note(filtered_count, &filtered_reasons) {
+                    contents.push(Content::text(note));
+                }
+                info!(
+                    num_images = images.len(),
+                    filtered_count, "Image generation successful."
                 );
-                error!("{}", error_msg);
-                return error_msg;
+                Ok(CallToolResult::success(contents))
             }
+            Err(e) => Ok(self.retried_tool_error("Error generating image", &e, attempts)),
         }
+    }
 
-        match generate_image_from_gemini(
-            &args.prompt,
-            args.aspect_ratio.as_deref(),
+    #[tool(
+        description = "Edit an image from the gallery or an uploaded image: add or remove objects (optionally limited by a mask), extend the image beyond its borders, or replace the background. The edited images are saved as new gallery images, the source is kept."
+    )]
+    async fn edit_image(
+        &self,
+        #[tool(aggr)] args: EditImageArgs,
+        ct: CancellationToken,
+    ) -> Result<CallToolResult, rmcp::Error> {
+        info!(
+            filename = ?args.filename,
+            mode = args.mode.as_str(),
+            prompt = %args.prompt,
+            provider = ?args.provider,
+            model = ?args.model,
+            "Received image edit request"
+        );
+
+        let (provider, request) = match self.edit_request(&args).await {
+            Ok(prepared) => prepared,
+            Err(e) => return Ok(self.tool_error("Invalid image edit request", &e)),
+        };
+        let progress = Progress::current();
+        progress.report(Stage::Queued).await;
+
+        let details = ImageMetadata {
+            operation: "edit".to_string(),
+            source: args.filename.clone(),
+            edit_mode: Some(request.mode),
+            prompt: Some(request.prompt.clone()),
+            negative_prompt: request.negative_prompt.clone(),
+            aspect_ratio: args.aspect_ratio.clone(),
+            seed: request.seed,
+            tags: normalize_tags(args.tags.as_deref()),
+            provider: provider.name().to_string(),
+            model: request.model.clone(),
+            ..Default::default()
+        };
+        progress.report(Stage::Sending).await;
+        let (output, attempts) = self.retry_policy.run(&ct, || provider.edit(&request)).await;
+        let result = match output {
+            Ok(output) => {
+                progress.report(Stage::Decoding).await;
+                save_images(output.images, &self.resources_path, &details)
+                    .await
+                    .map(|images| (images, output.filtered_count, output.filtered_reasons))
+            }
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok((images, filtered_count, filtered_reasons)) => {
+                progress.report(Stage::Saved).await;
+                self.record_metadata(&images).await;
+                self.notify_images_saved(&images).await;
+
+                let mut details = format!(
+                    "Source: {}\nEdit mode: {}\nProvider: {}\nModel: {}",
+                    args.filename.as_deref().unwrap_or("uploaded image"),
+                    request.mode.as_str(),
+                    provider.name(),
+                    request.model
+                );
+                if let Some(seed) = request.seed {
+                    details.push_str(&format!("\nSeed: {}", seed));
+                }
+                push_attempts(&mut details, attempts);
+                let mut contents = self.image_results(&images, &details);
+                if let Some(note) = filtered_note(filtered_count, &filtered_reasons) {
+                    contents.push(Content::text(note));
+                }
+                info!(
+                    num_images = images.len(),
+                    filtered_count, "Image edit successful."
+                );
+                Ok(CallToolResult::success(contents))
+            }
+            Err(e) => Ok(self.retried_tool_error("Error editing image", &e, attempts)),
+        }
+    }
+
+    #[tool(
+        description = "Upscale a gallery image by 2x or 4x, e.g. for print, with a high quality resize. The result is saved as a new gallery image, the source is kept."
+    )]
+    async fn upscale_image(
+        &self,
+        #[tool(aggr)] args: UpscaleImageArgs,
+    ) -> Result<CallToolResult, rmcp::Error> {
+        info!(?args, "Received image upscale request");
+
+        let progress = Progress::current();
+        let result = match self.upscale(&args).await {
+            Ok(image) => {
+                let details = ImageMetadata {
+                    operation: "upscale".to_string(),
+                    source: Some(args.filename.clone()),
+                    upscale_factor: Some(args.factor),
+                    provider: "local".to_string(),
+                    model: "lanczos3".to_string(),
+                    ..Default::default()
+                };
+                progress.report(Stage::Decoding).await;
+                save_images(vec![image], &self.resources_path, &details).await
+            }
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(images) => {
+                progress.report(Stage::Saved).await;
+                self.record_metadata(&images).await;
+                self.notify_images_saved(&images).await;
+
+                let details = format!(
+                    "Source: {}\nUpscale factor: {}\nMethod: local Lanczos resize",
+                    args.filename,
+                    args.factor.as_str()
+                );
+                info!("Image upscale successful.");
+                Ok(CallToolResult::success(
+                    self.image_results(&images, &details),
+                ))
+            }
+            Err(e) => Ok(self.tool_error("Error upscaling image", &e)),
+        }
+    }
+
+    #[tool(
+        description = "Look up how a gallery image was made: the prompt, negative prompt, aspect ratio, seed, provider, model and, for edits and upscales, the source image."
+    )]
+    async fn get_image_metadata(
+        &self,
+        #[tool(param)]
+        #[schemars(description = "The filename or id of the gallery image")]
+        image: String,
+    ) -> Result<CallToolResult, rmcp::Error> {
+        info!(%image, "Received image metadata request");
+        let error = match self.metadata.get(&image).await {
+            Ok(Some(entry)) => match serde_json::to_string_pretty(&entry) {
+                Ok(json) => return Ok(CallToolResult::success(vec![Content::text(json)])),
+                Err(e) => ProviderError::Internal(format!("Failed to serialize metadata: {}", e)),
+            },
+            Ok(None) => {
+                ProviderError::InvalidParameter(format!("No metadata found for image: {}", image))
+            }
+            Err(e) => e.into(),
+        };
+        Ok(self.tool_error("Error reading image metadata", &error))
+    }
+
+    #[tool(
+        description = "Search the gallery: full-text search over the prompts, filters by model, aspect ratio, tag and creation date, sorting and pagination. Returns a JSON page with the URL and metadata of each image, followed by a thumbnail of each image when inline results are enabled. Pass next_cursor as cursor to get the next page."
+    )]
+    async fn search_images(
+        &self,
+        #[tool(aggr)] query: SearchQuery,
+    ) -> Result<CallToolResult, rmcp::Error> {
+        info!(?query, "Received image search request");
+        let images_dir = self.resources_path.join("images");
+        let entries = match search::gallery_metadata(&self.metadata, &images_dir).await {
+            Ok(entries) => entries,
+            Err(e) => return Ok(self.tool_error("Error searching images", &e.into())),
+        };
+        let page = match search::search(entries, &query, &self.image_base_url()) {
+            Ok(page) => page,
+            Err(error_msg) => {
+                return Ok(self.tool_error(
+                    "Invalid image search request",
+                    &ProviderError::InvalidParameter(error_msg),
+                ));
+            }
+        };
+
+        let json = serde_json::to_string_pretty(&page)
+            .unwrap_or_else(|e| format!("Failed to serialize search results: {}", e));
+        let mut contents = vec![Content::text(json)];
+        if self.result_mode.includes_inline() {
+            // Thumbnails keep their format, not every client accepts WebP
+            let query = VariantQuery {
+                w: Some(THUMBNAIL_DIMENSION),
+                h: Some(THUMBNAIL_DIMENSION),
+                format: None,
+            };
+            for image in &page.images {
+                let filename = &image.metadata.filename;
+                match thumbnails::variant(&self.resources_path, filename, &query).await {
+                    Ok(thumbnail) => contents.push(Content::image(
+                        base64::engine::general_purpose::STANDARD.encode(&thumbnail.bytes),
+                        thumbnail.mime_type,
+                    )),
+                    Err(e) => error!(%filename, "Failed to create thumbnail: {}", e),
+                }
+            }
+        }
+        info!(
+            total = page.total,
+            num_images = page.images.len(),
+            "Image search successful."
+        );
+        Ok(CallToolResult::success(contents))
+    }
+
+    #[tool(
+        description = "Pin a gallery image so that the garbage collection never removes it, whatever its age and the size of the gallery. Use it for results that must be kept."
+    )]
+    async fn pin_image(
+        &self,
+        #[tool(param)]
+        #[schemars(description = "The filename or id of the gallery image")]
+        image: String,
+    ) -> Result<CallToolResult, rmcp::Error> {
+        info!(%image, "Received pin image request");
+        match self.set_pinned(&image, true).await {
+            Ok(message) => Ok(CallToolResult::success(vec![Content::text(message)])),
+            Err(e) => Ok(self.tool_error("Error pinning image", &e.into())),
+        }
+    }
+
+    #[tool(
+        description = "Unpin a gallery image pinned with pin_image, so that the garbage collection can remove it again."
+    )]
+    async fn unpin_image(
+        &self,
+        #[tool(param)]
+        #[schemars(description = "The filename or id of the gallery image")]
+        image: String,
+    ) -> Result<CallToolResult, rmcp::Error> {
+        info!(%image, "Received unpin image request");
+        match self.set_pinned(&image, false).await {
+            Ok(message) => Ok(CallToolResult::success(vec![Content::text(message)])),
+            Err(e) => Ok(self.tool_error("Error unpinning image", &e.into())),
+        }
+    }
+
+    #[tool(
+        description = "Delete a gallery image and its metadata, e.g. a failed result that should not be kept. Pinned images have to be unpinned first."
+    )]
+    async fn delete_image(
+        &self,
+        #[tool(param)]
+        #[schemars(description = "The filename or id of the gallery image")]
+        image: String,
+    ) -> Result<CallToolResult, rmcp::Error> {
+        info!(%image, "Received delete image request");
+        match images::delete(&self.resources_path, &self.metadata, &image).await {
+            Ok(entry) => {
+                self.notify_gallery_changed(&[entry.filename.as_str()])
+                    .await;
+                Ok(CallToolResult::success(vec![Content::text(format!(
+                    "Deleted image {}",
+                    entry.filename
+                ))]))
+            }
+            Err(e) => Ok(self.tool_error("Error deleting image", &e.into())),
+        }
+    }
+
+    #[tool(
+        description = "Rename a gallery image, e.g. to give it a descriptive name. The metadata moves with it, the old URL stops working. Returns the new URL."
+    )]
+    async fn rename_image(
+        &self,
+        #[tool(aggr)] args: RenameImageArgs,
+    ) -> Result<CallToolResult, rmcp::Error> {
+        info!(image = %args.image, new_filename = %args.new_filename, "Received rename image request");
+        match images::rename(
             &self.resources_path,
+            &self.metadata,
+            &args.image,
+            &args.new_filename,
         )
         .await
         {
-            Ok(filenames) => {
-                // Return the URL to the generated image using the configured address and port
-                let urls = filenames
+            Ok((old_filename, entry)) => {
+                self.notify_gallery_changed(&[old_filename.as_str(), entry.filename.as_str()])
+                    .await;
+                Ok(CallToolResult::success(vec![Content::text(format!(
+                    "Renamed image {} to {}\nURL: {}/images/{}",
+                    old_filename,
+                    entry.filename,
+                    self.image_base_url(),
+                    entry.filename
+                ))]))
+            }
+            Err(e) => Ok(self.tool_error("Error renaming image", &e.into())),
+        }
+    }
+
+    #[tool(
+        description = "Read the metadata embedded in an image file: the PNG text chunks or the XMP properties of a JPEG or WebP file. Images saved by this server carry their prompt, negative prompt, model, seed, aspect ratio, creation time and the server version, other tools often store their own parameters the same way."
+    )]
+    async fn read_image_metadata(
+        &self,
+        #[tool(aggr)] args: ReadImageMetadataArgs,
+    ) -> Result<CallToolResult, rmcp::Error> {
+        info!(filename = ?args.filename, "Received embedded metadata request");
+        let image = match self
+            .load_image(args.filename.as_deref(), args.image.as_deref())
+            .await
+        {
+            Ok(image) => image,
+            Err(e) => return Ok(self.tool_error("Error reading embedded metadata", &e)),
+        };
+
+        let error = match provenance::read(&image.bytes) {
+            Ok(provenance::EmbeddedMetadata { format, fields }) => {
+                let fields = fields
+                    .into_iter()
+                    .map(|(key, value)| (key, serde_json::Value::String(value)))
+                    .collect::<serde_json::Map<_, _>>();
+                match serde_json::to_string_pretty(&serde_json::json!({
+                    "format": format,
+                    "fields": fields,
+                })) {
+                    Ok(json) => return Ok(CallToolResult::success(vec![Content::text(json)])),
+                    Err(e) => {
+                        ProviderError::Internal(format!("Failed to serialize metadata: {}", e))
+                    }
+                }
+            }
+            // Unsupported or broken files are the caller's to fix
+            Err(error_msg) => ProviderError::InvalidParameter(error_msg),
+        };
+        Ok(self.tool_error("Error reading embedded metadata", &error))
+    }
+
+    #[tool(
+        description = "List the configured image providers and models with the aspect ratios, maximum number of images, and whether negative prompts, seeds, guidance scale, prompt enhancement and editing are supported. Use the provider and model values with generate_image."
+    )]
+    async fn list_models(&self) -> String {
+        info!("Received list models request");
+
+        #[derive(Serialize)]
+        struct ModelEntry<'a> {
+            provider: &'static str,
+            default: bool,
+            #[serde(flatten)]
+            model: &'a ModelInfo,
+        }
+
+        let providers = self
+            .providers
+            .names()
+            .into_iter()
+            .filter_map(|name| self.providers.get(Some(name)).ok())
+            .collect::<Vec<_>>();
+        let entries = providers
+            .iter()
+            .flat_map(|provider| {
+                let is_default_provider = provider.name() == self.providers.default_provider();
+                provider
+                    .models()
                     .iter()
-                    .map(|filename| {
-                        format!(
-                            "http://{}:{}/images/{}",
-                            self.image_resource_server_addr,
-                            self.server_port, // Use the configured port
-                            filename
-                        )
+                    .enumerate()
+                    .map(move |(index, model)| ModelEntry {
+                        provider: provider.name(),
+                        default: is_default_provider && index == 0,
+                        model,
                     })
-                    .collect::<Vec<String>>()
-                    .join("\n");
-                info!(num_images = filenames.len(), "Image generation successful.");
-                urls
-            }
+            })
+            .collect::<Vec<_>>();
+
+        match serde_json::to_string_pretty(&entries) {
+            Ok(json) => json,
             Err(e) => {
-                error!("Error generating image: {}", e);
-                format!("Error generating image: {}", e)
+                error!("Failed to serialize models: {}", e);
+                format!("Failed to list models: {}", e)
             }
         }
     }
 }
 
 // Implement ServerHandler trait for our image generation server
-#[tool(tool_box)]
 impl ServerHandler for ImageGenerationServer {
+    async fn list_tools(
+        &self,
+        _request: PaginatedRequestParam,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<ListToolsResult, rmcp::Error> {
+        Ok(ListToolsResult {
+            next_cursor: None,
+            tools: Self::tool_box().list(),
+        })
+    }
+
+    // What #[tool(tool_box)] generates, with the progress token of the
+    // request made available to the tool
+    async fn call_tool(
+        &self,
+        request: CallToolRequestParam,
+        context: RequestContext<RoleServer>,
+    ) -> Result<CallToolResult, rmcp::Error> {
+        let progress = Progress::new(context.peer.clone(), self.progress_tokens.take(&context.id));
+        let context = ToolCallContext::new(self, request, context);
+        logging::session_scope(
+            self.session_id.clone(),
+            progress.scope(Self::tool_box().call(context)),
+        )
+        .await
+    }
+
     fn get_info(&self) -> ServerInfo {
         ServerInfo {
             server_info: Implementation {
                 name: "imagen3-mcp".into(),
                 version: "0.1.0".into(),
             },
-            instructions: Some(r#"
-Use the generate_image tool to create images from text descriptions. The returned URL can be used in markdown format like ![description](URL) to display the image.
-
-Before generating an image, please read the <Imagen_prompt_guide> section to understand how to create effective prompts.
-
-<Imagen_prompt_guide>
-## Prompt writing basics
-Description of the image to generate. Maximum prompt length is 480 tokens. A good prompt is descriptive and clear, and makes use of meaningful keywords and modifiers. Start by thinking of your subject, context, and style.
-Example Prompt: A sketch (style) of a modern apartment building (subject) surrounded by skyscrapers (context and background).
-1. Subject: The first thing to think about with any prompt is the subject: the object, person, animal, or scenery you want an image of.
-2. Context and background: Just as important is the background or context in which the subject will be placed. Try placing your subject in a variety of backgrounds. For example, a studio with a white background, outdoors, or indoor environments.
-3. Style: Finally, add the style of image you want. Styles can be general (painting, photograph, sketches) or very specific (pastel painting, charcoal drawing, isometric 3D). You can also combine styles.
-After you write a first version of your prompt, refine your prompt by adding more details until you get to the image that you want. Iteration is important. Start by establishing your core idea, and then refine and expand upon that core idea until the generated image is close to your vision.
-Imagen 3 can transform your ideas into detailed images, whether your prompts are short or long and detailed. Refine your vision through iterative prompting, adding details until you achieve the perfect result.
-Example Prompt: close-up photo of a woman in her 20s, street photography, movie still, muted orange warm tones
-Example Prompt: captivating photo of a woman in her 20s utilizing a street photography style. The image should look like a movie still with muted orange warm tones.
-Additional advice for Imagen prompt writing:
-- Use descriptive language: Employ detailed adjectives and adverbs to paint a clear picture for Imagen 3.
-- Provide context: If necessary, include background information to aid the AI's understanding.
-- Reference specific artists or styles: If you have a particular aesthetic in mind, referencing specific artists or art movements can be helpful.
-- Use prompt engineering tools: Consider exploring prompt engineering tools or resources to help you refine your prompts and achieve optimal results.
-- Enhancing the facial details in your personal and group images: Specify facial details as a focus of the photo (for example, use the word "portrait" in the prompt).
-## Generate text in images
-Imagen can add text into images, opening up more creative image generation possibilities. Use the following guidance to get the most out of this feature:
-- Iterate with confidence: You might have to regenerate images until you achieve the look you want. Imagen's text integration is still evolving, and sometimes multiple attempts yield the best results.
-- Keep it short: Limit text to 25 characters or less for optimal generation.
-- Multiple phrases: Experiment with two or three distinct phrases to provide additional information. Avoid exceeding three phrases for cleaner compositions.
-Example Prompt: A poster with the text "Summerland" in bold font as a title, underneath this text is the slogan "Summer never felt so good"
-- Guide Placement: While Imagen can attempt to position text as directed, expect occasional variations. This feature is continually improving.
-- Inspire font style: Specify a general font style to subtly influence Imagen's choices. Don't rely on precise font replication, but expect creative interpretations.
-- Font size: Specify a font size or a general indication of size (for example, small, medium, large) to influence the font size generation.
-## Advanced prompt writing techniques
-Use the following examples to create more specific prompts based on attributes like photography descriptors, shapes and materials, historical art movements, and image quality modifiers.
-### Photography
-- Prompt includes: "A photo of..."
-To use this style, start with using keywords that clearly tell Imagen that you're looking for a photograph. Start your prompts with "A photo of. . .". For example:
-Example Prompt: A photo of coffee beans in a kitchen on a wooden surface
-Example Prompt: A photo of a chocolate bar on a kitchen counter
-Example Prompt: A photo of a modern building with water in the background
-#### Photography modifiers
-In the following examples, you can see several photography-specific modifiers and parameters. You can combine multiple modifiers for more precise control.
-1. Camera Proximity - Close up, taken from far away
-   Example Prompt: A close-up photo of coffee beans
-   Example Prompt: A zoomed out photo of a small bag of coffee beans in a messy kitchen
-2. Camera Position - aerial, from below
-   Example Prompt: aerial photo of urban city with skyscrapers
-   Example Prompt: A photo of a forest canopy with blue skies from below
-3. Lighting - natural, dramatic, warm, cold
-   Example Prompt: studio photo of a modern arm chair, natural lighting
-   Example Prompt: studio photo of a modern arm chair, dramatic lighting
-4. Camera Settings - motion blur, soft focus, bokeh, portrait
-   Example Prompt: photo of a city with skyscrapers from the inside of a car with motion blur
-   Example Prompt: soft focus photograph of a bridge in an urban city at night
-5. Lens types - 35mm, 50mm, fisheye, wide angle, macro
-   Example Prompt: photo of a leaf, macro lens
-   Example Prompt: street photography, new york city, fisheye lens
-6. Film types - black and white, polaroid
-   Example Prompt: a polaroid portrait of a dog wearing sunglasses
-   Example Prompt: black and white photo of a dog wearing sunglasses
-### Illustration and art
-- Prompt includes: "A painting of...", "A sketch of..."
-Art styles vary from monochrome styles like pencil sketches, to hyper-realistic digital art. For example, the following images use the same prompt with different styles:
-"An [art style or creation technique] of an angular sporty electric sedan with skyscrapers in the background"
-Example Prompt: A technical pencil drawing of an angular...
-Example Prompt: A charcoal drawing of an angular...
-Example Prompt: A color pencil drawing of an angular...
-Example Prompt: A pastel painting of an angular...
-Example Prompt: A digital art of an angular...
-Example Prompt: An art deco (poster) of an angular...
-#### Shapes and materials
-- Prompt includes: "...made of...", "...in the shape of..."
-One of the strengths of this technology is that you can create imagery that is otherwise difficult or impossible. For example, you can recreate your company logo in different materials and textures.
-Example Prompt: a duffle bag made of cheese
-Example Prompt: neon tubes in the shape of a bird
-Example Prompt: an armchair made of paper, studio photo, origami style
-#### Historical art references
-- Prompt includes: "...in the style of..."
-Certain styles have become iconic over the years. The following are some ideas of historical painting or art styles that you can try.
-"generate an image in the style of [art period or movement] : a wind farm"
-Example Prompt: generate an image in the style of an impressionist painting: a wind farm
-Example Prompt: generate an image in the style of a renaissance painting: a wind farm
-Example Prompt: generate an image in the style of pop art: a wind farm
-### Image quality modifiers
-Certain keywords can let the model know that you're looking for a high-quality asset. Examples of quality modifiers include the following:
-- General Modifiers - high-quality, beautiful, stylized
-- Photos - 4K, HDR, Studio Photo
-- Art, Illustration - by a professional, detailed
-The following are a few examples of prompts without quality modifiers and the same prompt with quality modifiers.
-Example Prompt: (no quality modifiers): a photo of a corn stalk
-Example Prompt: (with quality modifiers): 4k HDR beautiful photo of a corn stalk taken by a professional photographer
-### Aspect ratios
-Imagen 3 image generation lets you set five distinct image aspect ratios.
-1. Square (1:1, default) - A standard square photo. Common uses for this aspect ratio include social media posts.
-2. Fullscreen (4:3) - This aspect ratio is commonly used in media or film. It is also the dimensions of most old (non-widescreen) TVs and medium format cameras. It captures more of the scene horizontally (compared to 1:1), making it a preferred aspect ratio for photography.
-   Example Prompt: close up of a musician's fingers playing the piano, black and white film, vintage (4:3 aspect ratio)
-   Example Prompt: A professional studio photo of french fries for a high end restaurant, in the style of a food magazine (4:3 aspect ratio)
-3. Portrait full screen (3:4) - This is the fullscreen aspect ratio rotated 90 degrees. This lets to capture more of the scene vertically compared to the 1:1 aspect ratio.
-   Example Prompt: a woman hiking, close of her boots reflected in a puddle, large mountains in the background, in the style of an advertisement, dramatic angles (3:4 aspect ratio)
-   Example Prompt: aerial shot of a river flowing up a mystical valley (3:4 aspect ratio)
-4. Widescreen (16:9) - This ratio has replaced 4:3 and is now the most common aspect ratio for TVs, monitors, and mobile phone screens (landscape). Use this aspect ratio when you want to capture more of the background (for example, scenic landscapes).
-   Example Prompt: a man wearing all white clothing sitting on the beach, close up, golden hour lighting (16:9 aspect ratio)
-5. Portrait (9:16) - This ratio is widescreen but rotated. This a relatively new aspect ratio that has been popularized by short form video apps (for example, YouTube shorts). Use this for tall objects with strong vertical orientations such as buildings, trees, waterfalls, or other similar objects.
-   Example Prompt: a digital render of a massive skyscraper, modern, grand, epic with a beautiful sunset in the background (9:16 aspect ratio)
-### Photorealistic images
-Different versions of the image generation model might offer a mix of artistic and photorealistic output. Use the following wording in prompts to generate more photorealistic output, based on the subject you want to generate.
-Note: Take these keywords as general guidance when you try to create photorealistic images. They aren't required to achieve your goal.
-| Use case | Lens type | Focal lengths | Additional details |
-| --- | --- | --- | --- |
-| People (portraits) | Prime, zoom | 24-35mm | black and white film, Film noir, Depth of field, duotone (mention two colors) |
-| Food, insects, plants (objects, still life) | Macro | 60-105mm | High detail, precise focusing, controlled lighting |
-| Sports, wildlife (motion) | Telephoto zoom | 100-400mm | Fast shutter speed, Action or movement tracking |
-| Astronomical, landscape (wide-angle) | Wide-angle | 10-24mm | Long exposure times, sharp focus, long exposure, smooth water or clouds |
-#### Portraits
-| Use case | Lens type | Focal lengths | Additional details |
-| --- | --- | --- | --- |
-| People (portraits) | Prime, zoom | 24-35mm | black and white film, Film noir, Depth of field, duotone (mention two colors) |
-Using several keywords from the table, Imagen can generate the following portraits:
-Example Prompt: A woman, 35mm portrait, blue and grey duotones
-Example Prompt: A woman, 35mm portrait, film noir
-#### Objects:
-| Use case | Lens type | Focal lengths | Additional details |
-| --- | --- | --- | --- |
-| Food, insects, plants (objects, still life) | Macro | 60-105mm | High detail, precise focusing, controlled lighting |
-Using several keywords from the table, Imagen can generate the following object images:
-Example Prompt: leaf of a prayer plant, macro lens, 60mm
-Example Prompt: a plate of pasta, 100mm Macro lens
-#### Motion
-| Use case | Lens type | Focal lengths | Additional details |
-| --- | --- | --- | --- |
-| Sports, wildlife (motion) | Telephoto zoom | 100-400mm | Fast shutter speed, Action or movement tracking |
-Using several keywords from the table, Imagen can generate the following motion images:
-Example Prompt: a winning touchdown, fast shutter speed, movement tracking
-Example Prompt: A deer running in the forest, fast shutter speed, movement tracking
-#### Wide-angle
-| Use case | Lens type | Focal lengths | Additional details |
-| --- | --- | --- | --- |
-| Astronomical, landscape (wide-angle) | Wide-angle | 10-24mm | Long exposure times, sharp focus, long exposure, smooth water or clouds |
-Using several keywords from the table, Imagen can generate the following wide-angle images:
-Example Prompt: an expansive mountain range, landscape wide angle 10mm
-Example Prompt: a photo of the moon, astro photography, wide angle 10mm
-</Imagen_prompt_guide>
-            "#.trim().into()),
+            instructions: Some(
+                "Use generate_image to create images from text, edit_image to change an existing image and upscale_image to enlarge one; list_models shows what each model supports. Show returned URLs as markdown images like ![description](URL). Earlier images are resources under imagen://images/{filename}, the gallery tools (search_images, get_image_metadata, pin_image, delete_image, rename_image) manage them. Failed calls return a JSON error with a code and a hint. Before writing a prompt, read the imagen_prompt_guide prompt."
+                    .into(),
+            ),
             capabilities: ServerCapabilities::builder()
+                .enable_logging()
                 .enable_tools()
+                .enable_prompts()
+                .enable_resources()
+                .enable_resources_subscribe()
+                .enable_resources_list_changed()
                 .build(),
             ..Default::default()
         }
     }
+
+    async fn list_prompts(
+        &self,
+        _request: PaginatedRequestParam,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<ListPromptsResult, rmcp::Error> {
+        Ok(ListPromptsResult {
+            prompts: self.prompts.list(),
+            next_cursor: None,
+        })
+    }
+
+    async fn get_prompt(
+        &self,
+        GetPromptRequestParam { name, arguments }: GetPromptRequestParam,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<GetPromptResult, rmcp::Error> {
+        info!(%name, "Received prompt request");
+        self.prompts.get(&name, arguments)
+    }
+
+    async fn list_resources(
+        &self,
+        request: PaginatedRequestParam,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<ListResourcesResult, rmcp::Error> {
+        // The cursor is the offset of the next page
+        let offset = match request.and_then(|request| request.cursor) {
+            Some(cursor) => cursor
+                .parse::<usize>()
+                .map_err(|_| rmcp::Error::invalid_params("Invalid cursor", None))?,
+            None => 0,
+        };
+
+        let entries = resources::gallery_entries(&self.resources_path.join("images"))
+            .await
+            .map_err(|e| {
+                error!("Failed to list images: {}", e);
+                rmcp::Error::internal_error(format!("Failed to list images: {}", e), None)
+            })?;
+
+        let mut page = Vec::new();
+        if offset == 0 {
+            page.push(resources::gallery_resource());
+        }
+        page.extend(
+            entries
+                .iter()
+                .skip(offset)
+                .take(RESOURCES_PAGE_SIZE)
+                .map(resources::image_resource),
+        );
+        let next_offset = offset + RESOURCES_PAGE_SIZE;
+        Ok(ListResourcesResult {
+            resources: page,
+            next_cursor: (next_offset < entries.len()).then(|| next_offset.to_string()),
+        })
+    }
+
+    async fn list_resource_templates(
+        &self,
+        _request: PaginatedRequestParam,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<ListResourceTemplatesResult, rmcp::Error> {
+        Ok(ListResourceTemplatesResult {
+            resource_templates: vec![resources::image_resource_template()],
+            next_cursor: None,
+        })
+    }
+
+    async fn read_resource(
+        &self,
+        ReadResourceRequestParam { uri }: ReadResourceRequestParam,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<ReadResourceResult, rmcp::Error> {
+        info!(%uri, "Received resource read request");
+        let images_dir = self.resources_path.join("images");
+
+        if uri == resources::GALLERY_URI {
+            let entries = resources::gallery_entries(&images_dir).await.map_err(|e| {
+                error!("Failed to list images: {}", e);
+                rmcp::Error::internal_error(format!("Failed to list images: {}", e), None)
+            })?;
+            return Ok(ReadResourceResult {
+                contents: vec![resources::gallery_contents(&entries)],
+            });
+        }
+
+        let Some(filename) = resources::filename_from_uri(&uri) else {
+            return Err(rmcp::Error::resource_not_found(
+                format!("Unknown resource: {}", uri),
+                None,
+            ));
+        };
+        match tokio::fs::read(images_dir.join(filename)).await {
+            Ok(bytes) => Ok(ReadResourceResult {
+                contents: vec![resources::image_contents(filename, &bytes)],
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(
+                rmcp::Error::resource_not_found(format!("Image not found: {}", uri), None),
+            ),
+            Err(e) => {
+                error!(%uri, "Failed to read image: {}", e);
+                Err(rmcp::Error::internal_error(
+                    format!("Failed to read image: {}", e),
+                    None,
+                ))
+            }
+        }
+    }
+
+    async fn subscribe(
+        &self,
+        SubscribeRequestParam { uri }: SubscribeRequestParam,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<(), rmcp::Error> {
+        if uri != resources::GALLERY_URI && resources::filename_from_uri(&uri).is_none() {
+            return Err(rmcp::Error::resource_not_found(
+                format!("Unknown resource: {}", uri),
+                None,
+            ));
+        }
+        info!(%uri, "Client subscribed to resource");
+        self.subscriptions.lock().unwrap().insert(uri);
+        Ok(())
+    }
+
+    async fn unsubscribe(
+        &self,
+        UnsubscribeRequestParam { uri }: UnsubscribeRequestParam,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<(), rmcp::Error> {
+        info!(%uri, "Client unsubscribed from resource");
+        self.subscriptions.lock().unwrap().remove(&uri);
+        Ok(())
+    }
+
+    fn get_peer(&self) -> Option<Peer<RoleServer>> {
+        self.peer.clone()
+    }
+
+    fn set_peer(&mut self, peer: Peer<RoleServer>) {
+        self.subscribers
+            .add(&self.session_id, peer.clone(), self.subscriptions.clone());
+        let subscribers = self.subscribers.clone();
+        let session_id = self.session_id.clone();
+        let closed = self.session_closed.clone();
+        tokio::spawn(async move {
+            closed.cancelled().await;
+            subscribers.remove(&session_id);
+        });
+        tokio::spawn(logging::forward_logs(
+            peer.clone(),
+            self.session_id.clone(),
+            self.log_records.subscribe(),
+            self.log_level.clone(),
+            self.session_closed.clone(),
+        ));
+        self.peer = Some(peer);
+    }
+
+    async fn set_level(
+        &self,
+        SetLevelRequestParam { level }: SetLevelRequestParam,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<(), rmcp::Error> {
+        info!(?level, "Client set the log level");
+        *self.log_level.lock().unwrap() = level;
+        Ok(())
+    }
 }
 
 // Create resources directory if it doesn't exist using cross-platform approach
@@ -442,6 +1653,86 @@ async fn ensure_log_dir() -> std::io::Result<PathBuf> {
     Ok(log_dir)
 }
 
+// The transport is taken from `--transport <mode>` on the command line, then
+// from MCP_TRANSPORT, and defaults to stdio
+fn transport_mode() -> Result<TransportMode, String> {
+    let mut args = env::args().skip(1);
+    let mut mode = None;
+    while let Some(arg) = args.next() {
+        if arg == "--transport" {
+            mode = args.next();
+        } else if let Some(value) = arg.strip_prefix("--transport=") {
+            mode = Some(value.to_string());
+        }
+    }
+    mode.or_else(|| env::var("MCP_TRANSPORT").ok())
+        .unwrap_or_else(|| "stdio".to_string())
+        .parse()
+        .map_err(|e| format!("Invalid transport: {}", e))
+}
+
+// Filenames arrive percent-encoded in URL paths, e.g. with spaces as %20
+fn decode_path_segment(segment: &str) -> String {
+    percent_encoding::percent_decode_str(segment)
+        .decode_utf8_lossy()
+        .into_owned()
+}
+
+// Body of PATCH /images/{id}
+#[derive(Debug, Deserialize)]
+struct RenameBody {
+    filename: String,
+}
+
+// The HTTP response for a failed delete or rename
+fn image_error_reply(e: ImageError) -> warp::reply::Response {
+    let status = match &e {
+        ImageError::NotFound(_) => warp::http::StatusCode::NOT_FOUND,
+        ImageError::Invalid(_) => warp::http::StatusCode::BAD_REQUEST,
+        ImageError::Conflict(_) => warp::http::StatusCode::CONFLICT,
+        ImageError::Failed(e) => {
+            error!("{}", e);
+            warp::http::StatusCode::INTERNAL_SERVER_ERROR
+        }
+    };
+    warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({ "error": e.to_string() })),
+        status,
+    )
+    .into_response()
+}
+
+// `imagen3-mcp gc [--dry-run]`: apply the retention policy once and print
+// what was removed
+async fn run_gc(resources_path: &Path, dry_run: bool) -> Result<(), String> {
+    let policy = RetentionPolicy::from_env()?;
+    if !policy.is_enabled() {
+        println!(
+            "No retention policy configured. Set RETENTION_MAX_AGE_DAYS, RETENTION_MAX_TOTAL_BYTES or RETENTION_MAX_IMAGES."
+        );
+        return Ok(());
+    }
+    let metadata = MetadataStore::new(resources_path);
+    let report = retention::collect(resources_path, &metadata, &policy, dry_run).await?;
+    let verb = if dry_run { "Would remove" } else { "Removed" };
+    for image in &report.removed {
+        println!(
+            "{} {} ({} bytes, created {}, {})",
+            verb, image.filename, image.bytes, image.created_at, image.reason
+        );
+    }
+    println!(
+        "{} {} images, {} bytes. Kept {} images ({} pinned), {} bytes.",
+        verb,
+        report.removed.len(),
+        report.freed_bytes,
+        report.kept_images,
+        report.pinned_images,
+        report.kept_bytes
+    );
+    Ok(())
+}
+
 // Handler to list images in the images directory
 async fn list_images(resources_path: PathBuf) -> Result<Vec<String>, std::io::Error> {
     let images_dir = resources_path.join("images");
@@ -450,12 +1741,10 @@ async fn list_images(resources_path: PathBuf) -> Result<Vec<String>, std::io::Er
     let mut entries = tokio::fs::read_dir(images_dir).await?;
     while let Some(entry) = entries.next_entry().await? {
         let path = entry.path();
-        if path.is_file() {
-            if let Some(filename) = path.file_name() {
-                if let Some(filename_str) = filename.to_str() {
-                    images.push(filename_str.to_string());
-                }
-            }
+        if path.is_file()
+            && let Some(filename_str) = path.file_name().and_then(|name| name.to_str())
+        {
+            images.push(filename_str.to_string());
         }
     }
 
@@ -478,7 +1767,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .with_ansi(false); // No ANSI colors in files
     // Optionally add .json() for structured JSON logs in the file
 
-    let console_layer = fmt::layer().with_writer(std::io::stdout); // Log to stdout
+    // Log to stderr, stdout carries the MCP protocol
+    let console_layer = fmt::layer().with_writer(std::io::stderr);
+
+    // Forward log records to connected MCP clients as notifications/message
+    let (mcp_log_layer, log_records) = McpLogLayer::new();
 
     // Use RUST_LOG environment variable for log level filtering (e.g., RUST_LOG=info,imagen3_mcp=debug)
     // Defaults to "info" if RUST_LOG is not set.
@@ -489,10 +1782,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .with(env_filter)
         .with(file_layer)
         .with(console_layer) // Add console layer
+        .with(mcp_log_layer)
         .init();
 
     info!(
-        "Tracing initialized. Logging to console and {}/{}",
+        "Tracing initialized. Logging to stderr, MCP clients and {}/{}",
         log_dir.display(),
         log_file_prefix
     );
@@ -507,7 +1801,16 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     };
 
+    // The gc subcommand runs the garbage collection instead of the server
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    if args.first().map(String::as_str) == Some("gc") {
+        let dry_run = args[1..].iter().any(|arg| arg == "--dry-run");
+        run_gc(&resources_path, dry_run).await?;
+        return Ok(());
+    }
+
     // Read configuration from environment variables
+    let transport_mode = transport_mode()?;
     let image_resource_server_addr =
         env::var("IMAGE_RESOURCE_SERVER_ADDR").unwrap_or_else(|_| "127.0.0.1".to_string());
     let server_port_str = env::var("SERVER_PORT").unwrap_or_else(|_| "9981".to_string());
@@ -516,30 +1819,193 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .map_err(|e| format!("Invalid SERVER_PORT: {}", e))?;
     let listen_addr_str =
         env::var("SERVER_LISTEN_ADDR").unwrap_or_else(|_| "127.0.0.1".to_string());
+    let result_mode: ResultMode = env::var("IMAGE_RESULT_MODE")
+        .unwrap_or_else(|_| "both".to_string())
+        .parse()
+        .map_err(|e| format!("Invalid IMAGE_RESULT_MODE: {}", e))?;
+    let inline_max_dimension: Option<u32> = env::var("INLINE_IMAGE_MAX_DIMENSION")
+        .ok()
+        .map(|value| value.parse())
+        .transpose()
+        .map_err(|e| format!("Invalid INLINE_IMAGE_MAX_DIMENSION: {}", e))?;
+
+    // Check that the default provider has what it needs
+    let providers = ProviderRegistry::from_env()?;
+    if let Err(e) = providers.get(None) {
+        error!("{} Image generation will fail.", e);
+        std::process::exit(1);
+    }
+    info!(
+        default_provider = providers.default_provider(),
+        "Image providers configured."
+    );
+
+    // Custom prompts are read from PROMPTS_DIR, or the prompts folder next to the artifacts
+    let prompts_dir = env::var("PROMPTS_DIR").map(PathBuf::from).ok().or_else(|| {
+        ProjectDirs::from("cn", "hamflx", "imagen3-mcp")
+            .map(|dirs| dirs.data_local_dir().join("prompts"))
+    });
+    info!(prompts_dir = ?prompts_dir, "Custom prompts directory configured.");
+
+    let metadata = MetadataStore::new(&resources_path);
+
+    let retry_policy = RetryPolicy::from_env()?;
+    info!(policy = ?retry_policy, "Retry policy configured.");
+
+    let subscribers = Subscribers::default();
+    // Remove old images in the background when a retention policy is set
+    let retention_policy = RetentionPolicy::from_env()?;
+    if retention_policy.is_enabled() {
+        info!(policy = ?retention_policy, "Retention policy configured.");
+        tokio::spawn(retention::run_periodically(
+            resources_path.clone(),
+            metadata.clone(),
+            retention_policy,
+            subscribers.clone(),
+        ));
+    }
 
     // Create service for MCP
     let service = ImageGenerationServer {
+        providers,
+        prompts: PromptCatalogue::new(prompts_dir),
         resources_path: resources_path.clone(),
         image_resource_server_addr: image_resource_server_addr.clone(), // Clone for info log
         server_port,
+        result_mode,
+        inline_max_dimension,
+        retry_policy,
+        metadata: metadata.clone(),
+        session_id: nanoid::nanoid!(),
+        peer: None,
+        subscriptions: Arc::default(),
+        subscribers: subscribers.clone(),
+        log_records,
+        log_level: Arc::new(Mutex::new(DEFAULT_CLIENT_LOG_LEVEL)),
+        progress_tokens: ProgressTokens::default(),
+        session_closed: CancellationToken::new(),
     };
     info!(
         ?image_resource_server_addr,
-        server_port, "Image server configured."
+        server_port,
+        ?result_mode,
+        ?inline_max_dimension,
+        ?transport_mode,
+        "Image server configured."
     );
 
-    // Check if GEMINI_API_KEY is set
-    if env::var("GEMINI_API_KEY").is_err() {
-        error!("GEMINI_API_KEY environment variable is not set. Image generation will fail.");
-        std::process::exit(1);
-    } else {
-        info!("GEMINI_API_KEY found.");
-    }
-
     // Set up static file server with warp
     let images_path = resources_path.join("images");
     let resources_path_clone = resources_path.clone();
 
+    // Route for resized and converted images, /images/{filename}?w=256&format=webp.
+    // Requests without parameters fall through to the plain file route.
+    let variants_resources_path = resources_path.clone();
+    let image_variants_route = warp::path!("images" / String)
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and_then(move |filename: String, params: HashMap<String, String>| {
+            let resources_path = variants_resources_path.clone();
+            let filename = decode_path_segment(&filename);
+            async move {
+                let query = match VariantQuery::from_params(&params) {
+                    Ok(query) if query.is_empty() => return Err(warp::reject::not_found()),
+                    Ok(query) => query,
+                    Err(e) => {
+                        return Ok(warp::reply::with_status(
+                            e,
+                            warp::http::StatusCode::BAD_REQUEST,
+                        )
+                        .into_response());
+                    }
+                };
+                match thumbnails::variant(&resources_path, &filename, &query).await {
+                    Ok(variant) => Ok(warp::reply::with_header(
+                        variant.bytes,
+                        "content-type",
+                        variant.mime_type,
+                    )
+                    .into_response()),
+                    Err(VariantError::NotFound) => Err(warp::reject::not_found()),
+                    Err(VariantError::Invalid(e)) => Ok(warp::reply::with_status(
+                        e,
+                        warp::http::StatusCode::BAD_REQUEST,
+                    )
+                    .into_response()),
+                    Err(VariantError::Failed(e)) => {
+                        error!(%filename, "Failed to create image variant: {}", e);
+                        Ok(warp::reply::with_status(
+                            e,
+                            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+                        )
+                        .into_response())
+                    }
+                }
+            }
+        })
+        .with(warp::cors().allow_any_origin());
+
+    // Routes for deleting and renaming images, by id or filename. Renames take
+    // {"filename": "<new filename>"}. No CORS methods are allowed, so other
+    // sites cannot change the gallery from a browser. Connected clients are
+    // told about the change like after the tools.
+    let delete_resources_path = resources_path.clone();
+    let delete_metadata = metadata.clone();
+    let delete_subscribers = subscribers.clone();
+    let delete_image_route =
+        warp::path!("images" / String)
+            .and(warp::delete())
+            .and_then(move |image: String| {
+                let resources_path = delete_resources_path.clone();
+                let image = decode_path_segment(&image);
+                let metadata = delete_metadata.clone();
+                let subscribers = delete_subscribers.clone();
+                info!(%image, "Received request to delete image.");
+                async move {
+                    let reply = match images::delete(&resources_path, &metadata, &image).await {
+                        Ok(entry) => {
+                            subscribers
+                                .notify_gallery_changed(&[entry.filename.as_str()])
+                                .await;
+                            warp::reply::json(&entry).into_response()
+                        }
+                        Err(e) => image_error_reply(e),
+                    };
+                    Ok::<_, warp::Rejection>(reply)
+                }
+            });
+    let rename_resources_path = resources_path.clone();
+    let rename_metadata = metadata.clone();
+    let rename_subscribers = subscribers;
+    let rename_image_route = warp::path!("images" / String)
+        .and(warp::patch())
+        .and(warp::body::content_length_limit(16 * 1024))
+        .and(warp::body::json::<RenameBody>())
+        .and_then(move |image: String, body: RenameBody| {
+            let resources_path = rename_resources_path.clone();
+            let image = decode_path_segment(&image);
+            let metadata = rename_metadata.clone();
+            let subscribers = rename_subscribers.clone();
+            info!(%image, new_filename = %body.filename, "Received request to rename image.");
+            async move {
+                let reply = match images::rename(&resources_path, &metadata, &image, &body.filename)
+                    .await
+                {
+                    Ok((old_filename, entry)) => {
+                        subscribers
+                            .notify_gallery_changed(&[
+                                old_filename.as_str(),
+                                entry.filename.as_str(),
+                            ])
+                            .await;
+                        warp::reply::json(&entry).into_response()
+                    }
+                    Err(e) => image_error_reply(e),
+                };
+                Ok::<_, warp::Rejection>(reply)
+            }
+        });
+
     // Route for serving images
     let images_route = warp::path("images")
         .and(warp::fs::dir(images_path.clone())) // Clone for info log
@@ -561,14 +2027,144 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     });
 
+    // Routes for the metadata index: every entry, or one image by id or filename
+    let list_metadata = metadata.clone();
+    let list_metadata_route = warp::path("metadata")
+        .and(warp::path::end())
+        .and_then(move || {
+            let metadata = list_metadata.clone();
+            info!("Received request to list image metadata.");
+            async move {
+                match metadata.all().await {
+                    Ok(entries) => Ok(warp::reply::json(&entries)),
+                    Err(e) => {
+                        error!("Failed to read metadata index: {}", e);
+                        Err(warp::reject::not_found())
+                    }
+                }
+            }
+        })
+        .with(warp::cors().allow_any_origin());
+    let image_metadata = metadata.clone();
+    let image_metadata_route = warp::path!("metadata" / String)
+        .and_then(move |image: String| {
+            let metadata = image_metadata.clone();
+            info!(%image, "Received request for image metadata.");
+            async move {
+                match metadata.get(&image).await {
+                    Ok(Some(entry)) => Ok(warp::reply::json(&entry)),
+                    Ok(None) => Err(warp::reject::not_found()),
+                    Err(e) => {
+                        error!("Failed to read metadata index: {}", e);
+                        Err(warp::reject::not_found())
+                    }
+                }
+            }
+        })
+        .with(warp::cors().allow_any_origin());
+
+    // Route for searching the gallery, with the same parameters as search_images
+    let search_metadata = metadata.clone();
+    let search_images_dir = images_path.clone();
+    let search_base_url = format!("http://{}:{}", image_resource_server_addr, server_port);
+    let search_route = warp::path!("api" / "images")
+        .and(warp::get())
+        .and(warp::query::<SearchQuery>())
+        .and_then(move |query: SearchQuery| {
+            let metadata = search_metadata.clone();
+            let images_dir = search_images_dir.clone();
+            let base_url = search_base_url.clone();
+            info!(?query, "Received request to search images.");
+            async move {
+                let entries = match search::gallery_metadata(&metadata, &images_dir).await {
+                    Ok(entries) => entries,
+                    Err(e) => {
+                        error!("Failed to list images: {}", e);
+                        return Err(warp::reject::not_found());
+                    }
+                };
+                let reply = match search::search(entries, &query, &base_url) {
+                    Ok(page) => warp::reply::with_status(
+                        warp::reply::json(&page),
+                        warp::http::StatusCode::OK,
+                    ),
+                    Err(error_msg) => warp::reply::with_status(
+                        warp::reply::json(&serde_json::json!({ "error": error_msg })),
+                        warp::http::StatusCode::BAD_REQUEST,
+                    ),
+                };
+                Ok(reply)
+            }
+        })
+        .with(warp::cors().allow_any_origin());
+
+    // Route for listing the gallery with the size and dimensions of each image,
+    // for dashboards. /list-images keeps returning plain filenames.
+    let list_images_dir = images_path.clone();
+    let list_base_url = format!("http://{}:{}", image_resource_server_addr, server_port);
+    let list_images_v1_route = warp::path!("api" / "v1" / "images")
+        .and(warp::get())
+        .and(warp::query::<ListQuery>())
+        .and_then(move |query: ListQuery| {
+            let metadata = metadata.clone();
+            let images_dir = list_images_dir.clone();
+            let base_url = list_base_url.clone();
+            info!(?query, "Received request to list images with details.");
+            async move {
+                let entries = match search::gallery_metadata(&metadata, &images_dir).await {
+                    Ok(entries) => entries,
+                    Err(e) => {
+                        error!("Failed to list images: {}", e);
+                        return Err(warp::reject::not_found());
+                    }
+                };
+                let reply = match search::search(entries, &query.into(), &base_url) {
+                    Ok(page) => warp::reply::with_status(
+                        warp::reply::json(&search::summarize(page, &images_dir).await),
+                        warp::http::StatusCode::OK,
+                    ),
+                    Err(error_msg) => warp::reply::with_status(
+                        warp::reply::json(&serde_json::json!({ "error": error_msg })),
+                        warp::http::StatusCode::BAD_REQUEST,
+                    ),
+                };
+                Ok(reply)
+            }
+        })
+        .with(warp::cors().allow_any_origin());
+
+    // The gallery page, it only uses the JSON routes above
+    let gallery_route = warp::path::end()
+        .and(warp::get())
+        .map(|| warp::reply::html(GALLERY_HTML));
+
     // Combine all routes
-    let routes = images_route.or(list_images_route);
+    let routes = gallery_route
+        .or(image_variants_route)
+        .or(delete_image_route)
+        .or(rename_image_route)
+        .or(images_route)
+        .or(list_images_route)
+        .or(list_metadata_route)
+        .or(image_metadata_route)
+        .or(search_route)
+        .or(list_images_v1_route);
 
     // Parse server listen address
     let listen_addr: SocketAddr = format!("{}:{}", listen_addr_str, server_port)
         .parse()
         .map_err(|e| format!("Invalid SERVER_LISTEN_ADDR or SERVER_PORT: {}", e))?;
 
+    if transport_mode == TransportMode::Http {
+        // Serve MCP next to the image routes, one session per client
+        info!(address = %listen_addr, "Starting HTTP server for image resources and MCP (/mcp, /sse).");
+        warp::serve(routes.or(http_transport::routes(service, image_resource_server_addr)))
+            .run(listen_addr)
+            .await;
+        info!("HTTP server shut down.");
+        return Ok(());
+    }
+
     // Start HTTP server in a separate task
     info!(address = %listen_addr, "Starting HTTP server for image resources.");
     let http_server = warp::serve(routes).run(listen_addr);
@@ -580,9 +2176,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     // Start MCP server in the main task
     info!("Starting MCP server...");
-    let mcp_future = ServiceExt::serve(service, (tokio::io::stdin(), tokio::io::stdout()))
-        .await?
-        .waiting();
+    let transport = stdio_transport::transport(service.progress_tokens.clone());
+    let mcp_future = ServiceExt::serve(service, transport).await?.waiting();
 
     // Run MCP server to completion
     mcp_future.await?;
@@ -0,0 +1,168 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use tracing::warn;
+
+use crate::provider::{EditMode, SafetyAttribute, UpscaleFactor};
+
+// File in the resources directory with one JSON entry per line. Entries are
+// only appended, a later entry for the same id replaces the earlier one.
+const INDEX_FILE: &str = "metadata.jsonl";
+
+// Everything known about how an image was made
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ImageMetadata {
+    pub id: String,
+    pub filename: String,
+    pub mime_type: String,
+    pub created_at: String,
+    // "generate", "edit" or "upscale"
+    pub operation: String,
+    // The gallery image an edit or upscale was made from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub edit_mode: Option<EditMode>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upscale_factor: Option<UpscaleFactor>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompt: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub negative_prompt: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aspect_ratio: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub guidance_scale: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enhance_prompt: Option<bool>,
+    // Labels given by the client, for search_images
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    // Pinned images are kept by the garbage collection
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub pinned: bool,
+    pub provider: String,
+    pub model: String,
+    // What the provider returned besides the image, e.g. a rewritten prompt
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provider_response: Option<Value>,
+    // How the provider's safety filters rated the image
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub safety_attributes: Vec<SafetyAttribute>,
+}
+
+// The metadata index of the gallery, shared by all sessions
+#[derive(Debug, Clone)]
+pub struct MetadataStore {
+    path: PathBuf,
+    // Serializes writers, so that entries from parallel requests do not interleave
+    lock: Arc<Mutex<()>>,
+}
+
+impl MetadataStore {
+    pub fn new(resources_path: &Path) -> Self {
+        Self {
+            path: resources_path.join(INDEX_FILE),
+            lock: Arc::default(),
+        }
+    }
+
+    pub async fn append(&self, entries: &[ImageMetadata]) -> std::io::Result<()> {
+        let mut lines = String::new();
+        for entry in entries {
+            lines.push_str(&serde_json::to_string(entry)?);
+            lines.push('\n');
+        }
+
+        let _guard = self.lock.lock().await;
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        file.write_all(lines.as_bytes()).await?;
+        file.flush().await
+    }
+
+    // The latest entry of every image, oldest first
+    pub async fn all(&self) -> std::io::Result<Vec<ImageMetadata>> {
+        let contents = {
+            let _guard = self.lock.lock().await;
+            match tokio::fs::read_to_string(&self.path).await {
+                Ok(contents) => contents,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+                Err(e) => return Err(e),
+            }
+        };
+
+        let mut entries: Vec<ImageMetadata> = Vec::new();
+        let mut positions = HashMap::new();
+        for (number, line) in contents.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry: ImageMetadata = match serde_json::from_str(line) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    warn!(line = number + 1, "Skipping invalid metadata entry: {}", e);
+                    continue;
+                }
+            };
+            match positions.get(&entry.id) {
+                Some(&position) => entries[position] = entry,
+                None => {
+                    positions.insert(entry.id.clone(), entries.len());
+                    entries.push(entry);
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    // Drop every entry of the given files from the index. The index is
+    // rewritten, lines that cannot be parsed are kept as they are.
+    pub async fn remove(&self, filenames: &HashSet<String>) -> std::io::Result<()> {
+        #[derive(Deserialize)]
+        struct Entry {
+            filename: String,
+        }
+
+        let _guard = self.lock.lock().await;
+        let contents = match tokio::fs::read_to_string(&self.path).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let mut kept = String::new();
+        for line in contents.lines() {
+            if let Ok(entry) = serde_json::from_str::<Entry>(line)
+                && filenames.contains(&entry.filename)
+            {
+                continue;
+            }
+            kept.push_str(line);
+            kept.push('\n');
+        }
+
+        // Replace the index in one step, a crash must not truncate it
+        let temporary = self.path.with_extension("jsonl.tmp");
+        tokio::fs::write(&temporary, kept).await?;
+        tokio::fs::rename(&temporary, &self.path).await
+    }
+
+    // Look up an image by id or filename
+    pub async fn get(&self, key: &str) -> std::io::Result<Option<ImageMetadata>> {
+        Ok(self
+            .all()
+            .await?
+            .into_iter()
+            .find(|entry| entry.id == key || entry.filename == key))
+    }
+}
@@ -0,0 +1,107 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use rmcp::model::{ProgressNotificationParam, ProgressToken, RequestId};
+use rmcp::{Peer, RoleServer};
+use serde_json::Value;
+use tracing::{debug, warn};
+
+// The steps of a generation, edit or upscale, reported as progress 1 to 4
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    // The request is valid and about to be sent
+    Queued,
+    // Waiting for the provider
+    Sending,
+    // Converting the returned images and embedding their metadata
+    Decoding,
+    // The images are in the gallery
+    Saved,
+}
+
+impl Stage {
+    const COUNT: u32 = 4;
+
+    fn number(self) -> u32 {
+        match self {
+            Stage::Queued => 1,
+            Stage::Sending => 2,
+            Stage::Decoding => 3,
+            Stage::Saved => 4,
+        }
+    }
+}
+
+// rmcp drops the _meta of tools/call requests, so the transports take the
+// progress tokens from the raw messages and keep them here, by request id,
+// until the tool runs
+#[derive(Debug, Clone, Default)]
+pub struct ProgressTokens(Arc<Mutex<HashMap<RequestId, ProgressToken>>>);
+
+// The id and progress token of a raw tools/call request, if it has a token
+pub fn requested_token(message: &Value) -> Option<(RequestId, ProgressToken)> {
+    if message.get("method").and_then(Value::as_str) != Some("tools/call") {
+        return None;
+    }
+    let id = serde_json::from_value(message.get("id")?.clone()).ok()?;
+    let token =
+        serde_json::from_value(message.pointer("/params/_meta/progressToken")?.clone()).ok()?;
+    Some((id, token))
+}
+
+impl ProgressTokens {
+    pub fn insert(&self, (id, token): (RequestId, ProgressToken)) {
+        self.0.lock().unwrap().insert(id, token);
+    }
+
+    pub fn take(&self, id: &RequestId) -> Option<ProgressToken> {
+        self.0.lock().unwrap().remove(id)
+    }
+}
+
+// Sends notifications/progress for the running tool call, or nothing when
+// the client did not ask for them
+#[derive(Debug, Clone, Default)]
+pub struct Progress {
+    peer: Option<Peer<RoleServer>>,
+    token: Option<ProgressToken>,
+}
+
+tokio::task_local! {
+    static PROGRESS: Progress;
+}
+
+impl Progress {
+    pub fn new(peer: Peer<RoleServer>, token: Option<ProgressToken>) -> Self {
+        Self {
+            peer: Some(peer),
+            token,
+        }
+    }
+
+    // The progress of the tool call running in this task
+    pub fn current() -> Self {
+        PROGRESS.try_with(Clone::clone).unwrap_or_default()
+    }
+
+    // Run a tool call with this progress as the current one
+    pub async fn scope<F: Future>(self, future: F) -> F::Output {
+        PROGRESS.scope(self, future).await
+    }
+
+    pub async fn report(&self, stage: Stage) {
+        let (Some(peer), Some(token)) = (&self.peer, &self.token) else {
+            return;
+        };
+        debug!(?stage, %token, "Reporting progress.");
+        let param = ProgressNotificationParam {
+            progress_token: token.clone(),
+            progress: stage.number(),
+            total: Some(Stage::COUNT),
+        };
+        if let Err(e) = peer.notify_progress(param).await {
+            warn!("Failed to send progress notification: {}", e);
+        }
+    }
+}
@@ -0,0 +1,392 @@
+use std::path::{Path, PathBuf};
+
+use rmcp::model::{
+    GetPromptResult, JsonObject, Prompt, PromptArgument, PromptMessage, PromptMessageRole,
+};
+use serde::Deserialize;
+use tracing::{error, warn};
+
+// Sections of the Imagen prompt guide, embedded in the binary
+const GUIDE_BASICS: &str = include_str!("prompts/basics.md");
+const GUIDE_TEXT_IN_IMAGE: &str = include_str!("prompts/text_in_image.md");
+const GUIDE_PHOTOGRAPHY: &str = include_str!("prompts/photography.md");
+const GUIDE_ART: &str = include_str!("prompts/art.md");
+const GUIDE_QUALITY: &str = include_str!("prompts/quality.md");
+const GUIDE_ASPECT_RATIOS: &str = include_str!("prompts/aspect_ratios.md");
+const GUIDE_PHOTOREALISTIC: &str = include_str!("prompts/photorealistic.md");
+
+// A prompt built into the server
+struct BuiltinPrompt {
+    name: &'static str,
+    description: &'static str,
+    // (name, description, required)
+    arguments: &'static [(&'static str, &'static str, bool)],
+    render: fn(&Arguments) -> String,
+}
+
+const BUILTIN_PROMPTS: &[BuiltinPrompt] = &[
+    BuiltinPrompt {
+        name: "imagen_prompt_guide",
+        description: "The complete Imagen prompt writing guide.",
+        arguments: &[],
+        render: render_guide,
+    },
+    BuiltinPrompt {
+        name: "photography_prompt",
+        description: "Build a photographic image prompt from a subject and camera settings.",
+        arguments: &[
+            (
+                "subject",
+                "What the photo shows, e.g. \"coffee beans\"",
+                true,
+            ),
+            (
+                "context",
+                "The background or setting, e.g. \"on a wooden kitchen table\"",
+                false,
+            ),
+            (
+                "lens",
+                "Lens type or focal length, e.g. \"35mm\", \"macro\", \"fisheye\"",
+                false,
+            ),
+            (
+                "lighting",
+                "Lighting, e.g. \"natural\", \"dramatic\", \"golden hour\"",
+                false,
+            ),
+            (
+                "camera_proximity",
+                "e.g. \"close-up\", \"taken from far away\"",
+                false,
+            ),
+            ("camera_position", "e.g. \"aerial\", \"from below\"", false),
+            ("film_type", "e.g. \"black and white\", \"polaroid\"", false),
+        ],
+        render: render_photography,
+    },
+    BuiltinPrompt {
+        name: "poster_prompt",
+        description: "Build a prompt for a poster or other image that contains text.",
+        arguments: &[
+            (
+                "text",
+                "The main text in the image, ideally 25 characters or less",
+                true,
+            ),
+            ("slogan", "An optional second phrase", false),
+            ("subject", "What else the image shows", false),
+            (
+                "font_style",
+                "A general font style, e.g. \"bold sans-serif\"",
+                false,
+            ),
+            ("font_size", "e.g. \"small\", \"medium\", \"large\"", false),
+        ],
+        render: render_poster,
+    },
+    BuiltinPrompt {
+        name: "art_style_prompt",
+        description: "Build a prompt for an illustration or artwork in a specific style.",
+        arguments: &[
+            ("subject", "What the artwork shows", true),
+            (
+                "style",
+                "The art style or technique, e.g. \"charcoal drawing\", \"art deco poster\"",
+                true,
+            ),
+            (
+                "art_movement",
+                "A historical art movement, e.g. \"impressionism\"",
+                false,
+            ),
+            (
+                "material",
+                "What the subject is made of, e.g. \"paper\", \"neon tubes\"",
+                false,
+            ),
+        ],
+        render: render_art_style,
+    },
+    BuiltinPrompt {
+        name: "aspect_ratio_advisor",
+        description: "Choose the best aspect ratio for an image.",
+        arguments: &[
+            ("description", "What the image shows", true),
+            (
+                "use_case",
+                "Where the image will be used, e.g. \"phone wallpaper\"",
+                false,
+            ),
+        ],
+        render: render_aspect_ratio_advisor,
+    },
+];
+
+// A prompt loaded from the custom prompts directory. Placeholders in the
+// template are written as {{argument_name}}.
+#[derive(Debug, Deserialize)]
+struct CustomPrompt {
+    name: String,
+    description: Option<String>,
+    #[serde(default)]
+    arguments: Vec<PromptArgument>,
+    template: String,
+}
+
+// Prompt arguments as passed by the client
+struct Arguments(JsonObject);
+
+impl Arguments {
+    fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .get(name)
+            .and_then(|value| value.as_str())
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+    }
+}
+
+fn render_guide(_: &Arguments) -> String {
+    [
+        GUIDE_BASICS,
+        GUIDE_TEXT_IN_IMAGE,
+        GUIDE_PHOTOGRAPHY,
+        GUIDE_ART,
+        GUIDE_QUALITY,
+        GUIDE_ASPECT_RATIOS,
+        GUIDE_PHOTOREALISTIC,
+    ]
+    .join("")
+}
+
+// List the optional details that were provided, one per line
+fn details(args: &Arguments, names: &[(&str, &str)]) -> String {
+    names
+        .iter()
+        .filter_map(|(name, label)| {
+            args.get(name)
+                .map(|value| format!("- {}: {}\n", label, value))
+        })
+        .collect()
+}
+
+fn render_photography(args: &Arguments) -> String {
+    format!(
+        "Write an Imagen prompt for a photograph of {}, then call generate_image with it.\n\
+         Start the prompt with \"A photo of\" and include these details:\n{}\n\
+         Use the following guide to choose further modifiers:\n\n{}{}{}",
+        args.get("subject").unwrap_or_default(),
+        details(
+            args,
+            &[
+                ("context", "Context and background"),
+                ("lens", "Lens"),
+                ("lighting", "Lighting"),
+                ("camera_proximity", "Camera proximity"),
+                ("camera_position", "Camera position"),
+                ("film_type", "Film type"),
+            ],
+        ),
+        GUIDE_PHOTOGRAPHY,
+        GUIDE_PHOTOREALISTIC,
+        GUIDE_QUALITY,
+    )
+}
+
+fn render_poster(args: &Arguments) -> String {
+    let text = args.get("text").unwrap_or_default();
+    let mut message = format!(
+        "Write an Imagen prompt for an image with the text \"{}\", then call generate_image with it.\n",
+        text
+    );
+    if text.chars().count() > 25 {
+        message.push_str(
+            "The text is longer than 25 characters. Suggest a shorter wording to the user, since long text is often rendered incorrectly.\n",
+        );
+    }
+    message.push_str(&details(
+        args,
+        &[
+            ("slogan", "Slogan"),
+            ("subject", "Subject"),
+            ("font_style", "Font style"),
+            ("font_size", "Font size"),
+        ],
+    ));
+    message.push_str("\nFollow this guide:\n\n");
+    message.push_str(GUIDE_TEXT_IN_IMAGE);
+    message
+}
+
+fn render_art_style(args: &Arguments) -> String {
+    format!(
+        "Write an Imagen prompt for {} of {}, then call generate_image with it.\n{}\n\
+         Use the following guide to refine the prompt:\n\n{}{}",
+        args.get("style").unwrap_or_default(),
+        args.get("subject").unwrap_or_default(),
+        details(
+            args,
+            &[("art_movement", "In the style of"), ("material", "Made of"),],
+        ),
+        GUIDE_ART,
+        GUIDE_QUALITY,
+    )
+}
+
+fn render_aspect_ratio_advisor(args: &Arguments) -> String {
+    format!(
+        "Choose the best aspect ratio for an image of {} and explain the choice in one sentence.\n{}\n\
+         Only pick a ratio that the model supports, use the list_models tool to check. \
+         Pass the result as aspect_ratio to generate_image.\n\n{}",
+        args.get("description").unwrap_or_default(),
+        details(args, &[("use_case", "Use case")]),
+        GUIDE_ASPECT_RATIOS,
+    )
+}
+
+fn render_template(template: &str, args: &Arguments) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start..].find("}}") else {
+            break;
+        };
+        rendered.push_str(&rest[..start]);
+        let name = rest[start + 2..start + end].trim();
+        rendered.push_str(args.get(name).unwrap_or_default());
+        rest = &rest[start + end + 2..];
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+// The built-in prompts plus any prompts found in the custom prompts directory
+#[derive(Debug, Clone)]
+pub struct PromptCatalogue {
+    custom_dir: Option<PathBuf>,
+}
+
+impl PromptCatalogue {
+    pub fn new(custom_dir: Option<PathBuf>) -> Self {
+        Self { custom_dir }
+    }
+
+    // Custom prompts are read on every request so that new files show up
+    // without restarting the server.
+    fn custom_prompts(&self) -> Vec<CustomPrompt> {
+        let Some(dir) = &self.custom_dir else {
+            return Vec::new();
+        };
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    error!(path = %dir.display(), "Failed to read prompts directory: {}", e);
+                }
+                return Vec::new();
+            }
+        };
+
+        let mut prompts = Vec::new();
+        for path in entries.filter_map(|entry| entry.ok().map(|entry| entry.path())) {
+            if path.extension().and_then(|extension| extension.to_str()) != Some("json") {
+                continue;
+            }
+            match load_custom_prompt(&path) {
+                Ok(prompt) if BUILTIN_PROMPTS.iter().any(|p| p.name == prompt.name) => {
+                    warn!(path = %path.display(), name = %prompt.name, "Custom prompt has the same name as a built-in prompt, ignoring it.");
+                }
+                Ok(prompt) => prompts.push(prompt),
+                Err(e) => error!(path = %path.display(), "Failed to load custom prompt: {}", e),
+            }
+        }
+        prompts.sort_by(|a, b| a.name.cmp(&b.name));
+        prompts
+    }
+
+    pub fn list(&self) -> Vec<Prompt> {
+        let builtin = BUILTIN_PROMPTS.iter().map(|prompt| {
+            let arguments = prompt
+                .arguments
+                .iter()
+                .map(|&(name, description, required)| PromptArgument {
+                    name: name.to_string(),
+                    description: Some(description.to_string()),
+                    required: Some(required),
+                })
+                .collect::<Vec<_>>();
+            Prompt::new(
+                prompt.name,
+                Some(prompt.description),
+                (!arguments.is_empty()).then_some(arguments),
+            )
+        });
+        let custom = self.custom_prompts().into_iter().map(|prompt| {
+            Prompt::new(
+                prompt.name,
+                prompt.description,
+                (!prompt.arguments.is_empty()).then_some(prompt.arguments),
+            )
+        });
+        builtin.chain(custom).collect()
+    }
+
+    pub fn get(
+        &self,
+        name: &str,
+        arguments: Option<JsonObject>,
+    ) -> Result<GetPromptResult, rmcp::Error> {
+        let args = Arguments(arguments.unwrap_or_default());
+
+        let (description, required, text) =
+            if let Some(prompt) = BUILTIN_PROMPTS.iter().find(|prompt| prompt.name == name) {
+                let required = prompt
+                    .arguments
+                    .iter()
+                    .filter(|(_, _, required)| *required)
+                    .map(|(name, _, _)| name.to_string())
+                    .collect::<Vec<_>>();
+                (
+                    Some(prompt.description.to_string()),
+                    required,
+                    (prompt.render)(&args),
+                )
+            } else if let Some(prompt) = self
+                .custom_prompts()
+                .into_iter()
+                .find(|prompt| prompt.name == name)
+            {
+                let required = prompt
+                    .arguments
+                    .iter()
+                    .filter(|argument| argument.required == Some(true))
+                    .map(|argument| argument.name.clone())
+                    .collect::<Vec<_>>();
+                let text = render_template(&prompt.template, &args);
+                (prompt.description, required, text)
+            } else {
+                return Err(rmcp::Error::invalid_params(
+                    format!("Unknown prompt: {}", name),
+                    None,
+                ));
+            };
+
+        if let Some(missing) = required.iter().find(|name| args.get(name).is_none()) {
+            return Err(rmcp::Error::invalid_params(
+                format!("Missing required argument: {}", missing),
+                None,
+            ));
+        }
+
+        Ok(GetPromptResult {
+            description,
+            messages: vec![PromptMessage::new_text(PromptMessageRole::User, text)],
+        })
+    }
+}
+
+fn load_custom_prompt(path: &Path) -> Result<CustomPrompt, Box<dyn std::error::Error>> {
+    let contents = std::fs::read_to_string(path)?;
+    Ok(serde_json::from_str(&contents)?)
+}
@@ -0,0 +1,526 @@
+use std::io::Cursor;
+
+use png::text_metadata::{EncodableTextChunk, ITXtChunk, TEXtChunk};
+use serde_json::Value;
+
+use crate::metadata::ImageMetadata;
+
+// Written as the PNG "Software" keyword and the XMP CreatorTool
+const SOFTWARE: &str = concat!("imagen3-mcp ", env!("CARGO_PKG_VERSION"));
+
+// Namespace of the XMP properties written by this server
+const XMP_NAMESPACE: &str = "https://github.com/hamflx/imagen3-mcp/ns/1.0/";
+const XMP_PREFIX: &str = "imagen3";
+
+// Identifies the XMP packet in a JPEG APP1 segment
+const JPEG_XMP_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+// VP8X flag for an XMP chunk in a WebP file
+const WEBP_XMP_FLAG: u8 = 0x04;
+const WEBP_ALPHA_FLAG: u8 = 0x10;
+
+// The text fields written into an image: everything in the metadata index
+// except what depends on where the file is stored
+fn fields(metadata: &ImageMetadata) -> Vec<(String, String)> {
+    let mut fields = vec![("Software".to_string(), SOFTWARE.to_string())];
+    let Ok(Value::Object(entries)) = serde_json::to_value(metadata) else {
+        return fields;
+    };
+    for (key, value) in entries {
+        if matches!(
+            key.as_str(),
+            "filename" | "mime_type" | "provider_response" | "safety_attributes"
+        ) {
+            continue;
+        }
+        let text = match value {
+            Value::Null => continue,
+            Value::String(text) if text.is_empty() => continue,
+            Value::String(text) => text,
+            Value::Array(items) if items.is_empty() => continue,
+            Value::Array(items) => items
+                .iter()
+                .map(|item| match item {
+                    Value::String(text) => text.clone(),
+                    other => other.to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join(", "),
+            other => other.to_string(),
+        };
+        fields.push((key, text));
+    }
+    fields
+}
+
+// Write the provenance of an image into the file itself: text chunks for PNG
+// and an XMP packet for JPEG and WebP. Other formats are returned unchanged.
+pub fn embed(bytes: &[u8], mime_type: &str, metadata: &ImageMetadata) -> Result<Vec<u8>, String> {
+    let fields = fields(metadata);
+    match mime_type {
+        "image/png" => embed_png(bytes, &fields),
+        "image/jpeg" => embed_jpeg(bytes, &xmp_packet(&fields)),
+        "image/webp" => embed_webp(bytes, &xmp_packet(&fields)),
+        _ => Ok(bytes.to_vec()),
+    }
+}
+
+// The metadata read back out of an image file
+pub struct EmbeddedMetadata {
+    // "png", "jpeg" or "webp"
+    pub format: &'static str,
+    // XMP properties in file order, PNG text chunks grouped by chunk type
+    pub fields: Vec<(String, String)>,
+}
+
+// Read the text chunks or XMP properties back out of an image
+pub fn read(bytes: &[u8]) -> Result<EmbeddedMetadata, String> {
+    let (format, fields) = if bytes.starts_with(PNG_SIGNATURE) {
+        ("png", read_png(bytes)?)
+    } else if bytes.starts_with(b"\xff\xd8") {
+        ("jpeg", jpeg_xmp(bytes).map(parse_xmp).unwrap_or_default())
+    } else if bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        ("webp", webp_xmp(bytes).map(parse_xmp).unwrap_or_default())
+    } else {
+        return Err(
+            "Unsupported image format, supported formats are PNG, JPEG and WebP".to_string(),
+        );
+    };
+    Ok(EmbeddedMetadata { format, fields })
+}
+
+// Insert the text chunks right after IHDR, so that readers that stop at the
+// image data still see them
+fn embed_png(bytes: &[u8], fields: &[(String, String)]) -> Result<Vec<u8>, String> {
+    if !bytes.starts_with(PNG_SIGNATURE) || bytes.get(12..16) != Some(b"IHDR") {
+        return Err("Invalid PNG: the file does not start with an IHDR chunk".to_string());
+    }
+    let length = u32::from_be_bytes(bytes[8..12].try_into().unwrap()) as usize;
+    // Length, type, data and CRC
+    let ihdr_end = 8 + 12 + length;
+    if bytes.len() < ihdr_end {
+        return Err("Invalid PNG: the IHDR chunk is truncated".to_string());
+    }
+
+    let mut output = bytes[..ihdr_end].to_vec();
+    for (keyword, text) in fields {
+        // tEXt is Latin-1, anything else goes into a UTF-8 iTXt chunk
+        let result = if text.is_ascii() {
+            TEXtChunk::new(keyword.as_str(), text.as_str()).encode(&mut output)
+        } else {
+            ITXtChunk::new(keyword.as_str(), text.as_str()).encode(&mut output)
+        };
+        result.map_err(|e| format!("Failed to write PNG text chunk {}: {}", keyword, e))?;
+    }
+    output.extend_from_slice(&bytes[ihdr_end..]);
+    Ok(output)
+}
+
+fn read_png(bytes: &[u8]) -> Result<Vec<(String, String)>, String> {
+    let reader = png::Decoder::new(Cursor::new(bytes))
+        .read_info()
+        .map_err(|e| format!("Failed to read PNG: {}", e))?;
+    let info = reader.info();
+
+    let mut fields = Vec::new();
+    for chunk in &info.uncompressed_latin1_text {
+        fields.push((chunk.keyword.clone(), chunk.text.clone()));
+    }
+    for chunk in &info.compressed_latin1_text {
+        let text = chunk
+            .get_text()
+            .map_err(|e| format!("Failed to read PNG text chunk {}: {}", chunk.keyword, e))?;
+        fields.push((chunk.keyword.clone(), text));
+    }
+    for chunk in &info.utf8_text {
+        let text = chunk
+            .get_text()
+            .map_err(|e| format!("Failed to read PNG text chunk {}: {}", chunk.keyword, e))?;
+        fields.push((chunk.keyword.clone(), text));
+    }
+    Ok(fields)
+}
+
+fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\n' => escaped.push_str("&#10;"),
+            '\r' => escaped.push_str("&#13;"),
+            '\t' => escaped.push_str("&#9;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unescape_xml(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        unescaped.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let Some(end) = rest.find(';') else {
+            break;
+        };
+        let entity = &rest[1..end];
+        let c = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => match entity
+                .strip_prefix("#x")
+                .or_else(|| entity.strip_prefix("#X"))
+            {
+                Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                None => entity.strip_prefix('#').and_then(|dec| dec.parse().ok()),
+            }
+            .and_then(char::from_u32),
+        };
+        match c {
+            Some(c) => {
+                unescaped.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                unescaped.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+// An XMP packet with the fields as attributes of a single rdf:Description
+fn xmp_packet(fields: &[(String, String)]) -> String {
+    let mut attributes = String::new();
+    for (key, value) in fields {
+        let name = match key.as_str() {
+            "Software" => "xmp:CreatorTool".to_string(),
+            key => format!("{}:{}", XMP_PREFIX, key),
+        };
+        attributes.push_str(&format!("\n    {}=\"{}\"", name, escape_xml(value)));
+    }
+    format!(
+        concat!(
+            "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>\n",
+            "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">\n",
+            " <rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\n",
+            "  <rdf:Description rdf:about=\"\"\n",
+            "    xmlns:xmp=\"http://ns.adobe.com/xap/1.0/\"\n",
+            "    xmlns:{}=\"{}\"{}/>\n",
+            " </rdf:RDF>\n",
+            "</x:xmpmeta>\n",
+            "<?xpacket end=\"w\"?>"
+        ),
+        XMP_PREFIX, XMP_NAMESPACE, attributes
+    )
+}
+
+// The attributes of the rdf:Description elements of an XMP packet. Our own
+// properties lose their prefix, CreatorTool is reported as "Software" like in
+// PNG files and other properties keep their qualified name.
+fn parse_xmp(xmp: String) -> Vec<(String, String)> {
+    let mut fields = Vec::new();
+    let mut rest = xmp.as_str();
+    while let Some(start) = rest.find("<rdf:Description") {
+        rest = &rest[start + "<rdf:Description".len()..];
+        let end = rest.find('>').unwrap_or(rest.len());
+        let mut attributes = &rest[..end];
+        rest = &rest[end..];
+
+        while let Some(eq) = attributes.find('=') {
+            let name = attributes[..eq].trim();
+            let value = attributes[eq + 1..].trim_start();
+            let Some(quote) = value.chars().next().filter(|c| *c == '"' || *c == '\'') else {
+                break;
+            };
+            let Some(length) = value[1..].find(quote) else {
+                break;
+            };
+            let text = &value[1..1 + length];
+            attributes = &value[2 + length..];
+
+            if name.starts_with("xmlns:") || name == "rdf:about" {
+                continue;
+            }
+            let key = match name
+                .strip_prefix(XMP_PREFIX)
+                .and_then(|key| key.strip_prefix(':'))
+            {
+                Some(key) => key.to_string(),
+                None if name == "xmp:CreatorTool" => "Software".to_string(),
+                None => name.to_string(),
+            };
+            fields.push((key, unescape_xml(text)));
+        }
+    }
+    fields
+}
+
+// Insert the XMP packet as an APP1 segment after SOI and any JFIF header
+fn embed_jpeg(bytes: &[u8], xmp: &str) -> Result<Vec<u8>, String> {
+    if !bytes.starts_with(b"\xff\xd8") {
+        return Err("Invalid JPEG: missing start of image marker".to_string());
+    }
+    let segment_length = 2 + JPEG_XMP_HEADER.len() + xmp.len();
+    if segment_length > u16::MAX as usize {
+        return Err("The metadata is too large for a JPEG XMP segment".to_string());
+    }
+
+    let mut position = 2;
+    while bytes.get(position..position + 2) == Some(b"\xff\xe0") {
+        let Some(length) = bytes.get(position + 2..position + 4) else {
+            break;
+        };
+        position += 2 + u16::from_be_bytes([length[0], length[1]]) as usize;
+    }
+    let position = position.min(bytes.len());
+
+    let mut output = Vec::with_capacity(bytes.len() + 2 + segment_length);
+    output.extend_from_slice(&bytes[..position]);
+    output.extend_from_slice(b"\xff\xe1");
+    output.extend_from_slice(&(segment_length as u16).to_be_bytes());
+    output.extend_from_slice(JPEG_XMP_HEADER);
+    output.extend_from_slice(xmp.as_bytes());
+    output.extend_from_slice(&bytes[position..]);
+    Ok(output)
+}
+
+// The XMP packet of the first APP1 segment that has one
+fn jpeg_xmp(bytes: &[u8]) -> Option<String> {
+    let mut position = 2;
+    while let Some(&[0xff, marker, high, low]) = bytes.get(position..position + 4) {
+        // Start of scan, the image data follows
+        if marker == 0xda || marker == 0xd9 {
+            return None;
+        }
+        let length = u16::from_be_bytes([high, low]) as usize;
+        let segment = bytes.get(position + 4..position + 2 + length)?;
+        if marker == 0xe1
+            && let Some(xmp) = segment.strip_prefix(JPEG_XMP_HEADER)
+        {
+            return Some(String::from_utf8_lossy(xmp).into_owned());
+        }
+        position += 2 + length;
+    }
+    None
+}
+
+// The chunks of a WebP file as (fourcc, data)
+fn webp_chunks(bytes: &[u8]) -> Vec<([u8; 4], &[u8])> {
+    let mut chunks = Vec::new();
+    let mut position = 12;
+    while let Some(header) = bytes.get(position..position + 8) {
+        let fourcc = [header[0], header[1], header[2], header[3]];
+        let length = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        let Some(data) = bytes.get(position + 8..position + 8 + length) else {
+            break;
+        };
+        chunks.push((fourcc, data));
+        // Chunks are padded to an even size
+        position += 8 + length + (length & 1);
+    }
+    chunks
+}
+
+fn push_webp_chunk(output: &mut Vec<u8>, fourcc: &[u8; 4], data: &[u8]) {
+    output.extend_from_slice(fourcc);
+    output.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    output.extend_from_slice(data);
+    if data.len() & 1 == 1 {
+        output.push(0);
+    }
+}
+
+// The canvas size and whether there is alpha, from the header of a simple
+// (VP8 or VP8L) WebP image
+fn webp_canvas(fourcc: &[u8; 4], data: &[u8]) -> Option<(u32, u32, bool)> {
+    match fourcc {
+        b"VP8 " => {
+            let header = data.get(6..10)?;
+            let width = u16::from_le_bytes([header[0], header[1]]) & 0x3fff;
+            let height = u16::from_le_bytes([header[2], header[3]]) & 0x3fff;
+            Some((width as u32, height as u32, false))
+        }
+        b"VP8L" => {
+            let header = data.get(1..5)?;
+            let bits = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+            let width = (bits & 0x3fff) + 1;
+            let height = ((bits >> 14) & 0x3fff) + 1;
+            Some((width, height, bits & (1 << 28) != 0))
+        }
+        _ => None,
+    }
+}
+
+// Add an XMP chunk, converting a simple WebP file to the extended format
+// since only that can carry metadata
+fn embed_webp(bytes: &[u8], xmp: &str) -> Result<Vec<u8>, String> {
+    if bytes.len() < 12 || &bytes[..4] != b"RIFF" || &bytes[8..12] != b"WEBP" {
+        return Err("Invalid WebP: missing RIFF header".to_string());
+    }
+    let chunks = webp_chunks(bytes);
+    let Some(&(first, first_data)) = chunks.first() else {
+        return Err("Invalid WebP: the file has no chunks".to_string());
+    };
+
+    let mut output = bytes[..12].to_vec();
+    if &first == b"VP8X" {
+        let mut header = first_data.to_vec();
+        if let Some(flags) = header.first_mut() {
+            *flags |= WEBP_XMP_FLAG;
+        }
+        push_webp_chunk(&mut output, b"VP8X", &header);
+    } else {
+        let (width, height, alpha) = webp_canvas(&first, first_data)
+            .ok_or_else(|| "Invalid WebP: unknown image chunk".to_string())?;
+        let mut header = vec![
+            WEBP_XMP_FLAG | if alpha { WEBP_ALPHA_FLAG } else { 0 },
+            0,
+            0,
+            0,
+        ];
+        header.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+        header.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+        push_webp_chunk(&mut output, b"VP8X", &header);
+        push_webp_chunk(&mut output, &first, first_data);
+    }
+    for (fourcc, data) in &chunks[1..] {
+        if fourcc != b"XMP " {
+            push_webp_chunk(&mut output, fourcc, data);
+        }
+    }
+    push_webp_chunk(&mut output, b"XMP ", xmp.as_bytes());
+
+    let riff_size = (output.len() - 8) as u32;
+    output[4..8].copy_from_slice(&riff_size.to_le_bytes());
+    Ok(output)
+}
+
+fn webp_xmp(bytes: &[u8]) -> Option<String> {
+    webp_chunks(bytes)
+        .into_iter()
+        .find(|(fourcc, _)| fourcc == b"XMP ")
+        .map(|(_, data)| String::from_utf8_lossy(data).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{DynamicImage, ImageOutputFormat, Rgba, RgbaImage};
+
+    fn metadata() -> ImageMetadata {
+        ImageMetadata {
+            id: "V1StGXR8_Z".to_string(),
+            filename: "V1StGXR8_Z_20250601120000.png".to_string(),
+            mime_type: "image/png".to_string(),
+            created_at: "2025-06-01T12:00:00+02:00".to_string(),
+            operation: "generate".to_string(),
+            // Non-ASCII text and characters that need escaping in XML
+            prompt: Some("A café sign saying \"Soup & <Salad>\"\nat dusk".to_string()),
+            seed: Some(42),
+            tags: vec!["menu".to_string(), "café".to_string()],
+            provider: "imagen".to_string(),
+            model: "imagen-3.0-generate-002".to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn rgba_image() -> RgbaImage {
+        RgbaImage::from_fn(16, 8, |x, y| Rgba([x as u8 * 16, y as u8 * 32, 128, 255]))
+    }
+
+    fn encode(format: ImageOutputFormat) -> Vec<u8> {
+        let mut bytes = Cursor::new(Vec::new());
+        DynamicImage::ImageRgba8(rgba_image())
+            .write_to(&mut bytes, format)
+            .unwrap();
+        bytes.into_inner()
+    }
+
+    fn field<'a>(fields: &'a [(String, String)], key: &str) -> Option<&'a str> {
+        fields
+            .iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    // Embed the metadata, check that it reads back the same and that the
+    // file is still a valid image of the same size
+    fn assert_round_trip(bytes: &[u8], mime_type: &str, format: &str) {
+        let embedded = embed(bytes, mime_type, &metadata()).unwrap();
+        let read_back = read(&embedded).unwrap();
+        assert_eq!(read_back.format, format);
+        // PNG text chunks are read back grouped by chunk type
+        let mut expected = fields(&metadata());
+        expected.sort();
+        let mut actual = read_back.fields.clone();
+        actual.sort();
+        assert_eq!(actual, expected);
+
+        let fields = &read_back.fields;
+        assert_eq!(field(fields, "Software"), Some(SOFTWARE));
+        assert_eq!(field(fields, "prompt"), metadata().prompt.as_deref());
+        assert_eq!(field(fields, "seed"), Some("42"));
+        assert_eq!(field(fields, "tags"), Some("menu, café"));
+        assert_eq!(field(fields, "filename"), None);
+
+        let decoded = image::load_from_memory(&embedded).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (16, 8));
+    }
+
+    #[test]
+    fn png_round_trip() {
+        assert_round_trip(&encode(ImageOutputFormat::Png), "image/png", "png");
+    }
+
+    #[test]
+    fn jpeg_round_trip() {
+        assert_round_trip(&encode(ImageOutputFormat::Jpeg(90)), "image/jpeg", "jpeg");
+    }
+
+    #[test]
+    fn lossy_webp_round_trip() {
+        let image = rgba_image();
+        let bytes = webp::Encoder::from_rgba(&image, image.width(), image.height()).encode(80.0);
+        assert_round_trip(&bytes, "image/webp", "webp");
+    }
+
+    #[test]
+    fn lossless_webp_round_trip() {
+        let image = rgba_image();
+        let bytes =
+            webp::Encoder::from_rgba(&image, image.width(), image.height()).encode_lossless();
+        assert_round_trip(&bytes, "image/webp", "webp");
+    }
+
+    #[test]
+    fn embedding_twice_keeps_one_webp_xmp_chunk() {
+        let image = rgba_image();
+        let bytes = webp::Encoder::from_rgba(&image, image.width(), image.height()).encode(80.0);
+        let once = embed(&bytes, "image/webp", &metadata()).unwrap();
+        let twice = embed(&once, "image/webp", &metadata()).unwrap();
+        let xmp_chunks = webp_chunks(&twice)
+            .into_iter()
+            .filter(|(fourcc, _)| fourcc == b"XMP ")
+            .count();
+        assert_eq!(xmp_chunks, 1);
+        assert!(image::load_from_memory(&twice).is_ok());
+    }
+
+    #[test]
+    fn unsupported_formats_are_refused() {
+        assert!(read(b"GIF89a").is_err());
+        assert!(embed(b"not a png", "image/png", &metadata()).is_err());
+    }
+}
@@ -0,0 +1,476 @@
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rmcp::schemars;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::encoding::OutputFormat;
+
+mod error;
+mod gemini;
+mod imagen;
+mod mock;
+mod openai;
+mod retry;
+
+pub use error::{ErrorDetails, ProviderError};
+pub use gemini::GeminiProvider;
+pub use imagen::ImagenProvider;
+pub use mock::MockProvider;
+pub use openai::OpenAiProvider;
+pub use retry::RetryPolicy;
+
+pub type ProviderFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<ProviderOutput, ProviderError>> + Send + 'a>>;
+
+// Default timeouts of provider requests. Generating several images can take
+// minutes, a hung connection should not block a tool call forever.
+const DEFAULT_CONNECT_TIMEOUT_SECONDS: u64 = 10;
+const DEFAULT_REQUEST_TIMEOUT_SECONDS: u64 = 180;
+
+// Default base URL of the Google Generative Language API
+pub const GOOGLE_BASE_URL: &str = "https://generativelanguage.googleapis.com";
+
+// The aspect ratios every provider in this crate can produce
+pub const COMMON_ASPECT_RATIOS: &[&str] = &["1:1", "3:4", "4:3", "9:16", "16:9"];
+
+// What a model accepts, used to validate requests before they are sent
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct ModelCapabilities {
+    pub aspect_ratios: &'static [&'static str],
+    #[serde(rename = "max_number_of_images")]
+    pub max_sample_count: u32,
+    pub negative_prompt: bool,
+    // Whether the model takes a seed, reproducing an image depends on it
+    pub seed: bool,
+    pub guidance_scale: bool,
+    pub enhance_prompt: bool,
+    pub editing: bool,
+}
+
+// A model a provider can send requests to
+#[derive(Debug, Clone, Serialize)]
+pub struct ModelInfo {
+    pub id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alias: Option<&'static str>,
+    #[serde(flatten)]
+    pub capabilities: ModelCapabilities,
+}
+
+impl ModelInfo {
+    fn matches(&self, name: &str) -> bool {
+        self.id == name || self.alias == Some(name)
+    }
+}
+
+// Build the model list of a provider from its capability table, keeping the
+// configured default first. A default missing from the table is assumed to
+// behave like `fallback`.
+fn model_list(
+    table: &[(&'static str, Option<&'static str>, ModelCapabilities)],
+    default_model: &str,
+    fallback: ModelCapabilities,
+) -> Vec<ModelInfo> {
+    let mut models: Vec<ModelInfo> = table
+        .iter()
+        .map(|&(id, alias, capabilities)| ModelInfo {
+            id: id.to_string(),
+            alias,
+            capabilities,
+        })
+        .collect();
+    match models.iter().position(|model| model.matches(default_model)) {
+        Some(index) => models[..=index].rotate_right(1),
+        None => models.insert(
+            0,
+            ModelInfo {
+                id: default_model.to_string(),
+                alias: None,
+                capabilities: fallback,
+            },
+        ),
+    }
+    models
+}
+
+// Validated generation parameters shared by all providers
+#[derive(Debug, Clone)]
+pub struct GenerationRequest {
+    pub model: String,
+    pub prompt: String,
+    pub sample_count: u32,
+    pub aspect_ratio: Option<String>,
+    pub negative_prompt: Option<String>,
+    pub seed: Option<u32>,
+    pub add_watermark: Option<bool>,
+    pub enhance_prompt: Option<bool>,
+    pub guidance_scale: Option<f32>,
+    // Providers that cannot produce the format return their default, the
+    // images are converted after generation
+    pub output_format: Option<OutputFormat>,
+    pub quality: Option<u8>,
+}
+
+// What an edit does with the area marked in the mask
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, schemars::JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum EditMode {
+    // Add what the prompt describes
+    InpaintInsert,
+    // Remove what the prompt describes and fill in the background
+    InpaintRemove,
+    // Extend the image beyond its borders
+    Outpaint,
+    // Keep the subject and replace everything around it
+    BackgroundSwap,
+}
+
+impl EditMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            EditMode::InpaintInsert => "inpaint-insert",
+            EditMode::InpaintRemove => "inpaint-remove",
+            EditMode::Outpaint => "outpaint",
+            EditMode::BackgroundSwap => "background-swap",
+        }
+    }
+
+    // Instruction for models that are told what to do in plain text
+    pub fn instruction(self, prompt: &str, has_mask: bool) -> String {
+        let area = if has_mask {
+            "in the area marked white in the mask image"
+        } else {
+            "where it fits best"
+        };
+        match self {
+            EditMode::InpaintInsert => format!(
+                "Edit the image: add {} {}. Keep everything else unchanged.",
+                prompt, area
+            ),
+            EditMode::InpaintRemove => format!(
+                "Edit the image: remove {} {} and fill the gap with a matching background. Keep everything else unchanged.",
+                prompt, area
+            ),
+            EditMode::Outpaint => format!(
+                "Extend the image: the flat grey border is empty canvas, fill it so that the scene continues naturally. {} Keep the original part of the image unchanged.",
+                prompt
+            ),
+            EditMode::BackgroundSwap => format!(
+                "Edit the image: keep the main subject unchanged and replace the background with {}.",
+                prompt
+            ),
+        }
+    }
+}
+
+// Validated edit parameters shared by all providers. The mask has the size of
+// the image and is white where the image may change. For outpainting the
+// canvas is already extended and the mask covers the new area.
+#[derive(Debug)]
+pub struct EditRequest {
+    pub model: String,
+    pub prompt: String,
+    pub mode: EditMode,
+    pub image: ProviderImage,
+    pub mask: Option<ProviderImage>,
+    pub sample_count: u32,
+    pub negative_prompt: Option<String>,
+    pub seed: Option<u32>,
+}
+
+// How much an image is enlarged
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, schemars::JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum UpscaleFactor {
+    X2,
+    X4,
+}
+
+impl UpscaleFactor {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            UpscaleFactor::X2 => "x2",
+            UpscaleFactor::X4 => "x4",
+        }
+    }
+
+    pub fn multiplier(self) -> u32 {
+        match self {
+            UpscaleFactor::X2 => 2,
+            UpscaleFactor::X4 => 4,
+        }
+    }
+}
+
+// Fold a negative prompt into the prompt text, for APIs without a separate
+// parameter
+pub fn with_negative_prompt(prompt: String, negative_prompt: Option<&str>) -> String {
+    match negative_prompt {
+        Some(negative_prompt) => format!(
+            "{}\n\nDo not include any of the following: {}",
+            prompt, negative_prompt
+        ),
+        None => prompt,
+    }
+}
+
+// An image returned by a provider, before it is written to disk
+#[derive(Debug)]
+pub struct ProviderImage {
+    pub bytes: Vec<u8>,
+    pub mime_type: String,
+    // What the provider returned along with the image, kept in the metadata index
+    pub response: Option<serde_json::Value>,
+    // How the provider's safety filters rated the image, when it says
+    pub safety_attributes: Vec<SafetyAttribute>,
+}
+
+// A score from 0 to 1 for one category of the safety filters, e.g. "Violence"
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SafetyAttribute {
+    pub category: String,
+    pub score: f32,
+}
+
+// Everything a provider returned for a request. Some samples can be blocked
+// by the safety filters while the others come through.
+#[derive(Debug, Default)]
+pub struct ProviderOutput {
+    pub images: Vec<ProviderImage>,
+    // The number of samples the safety filters removed
+    pub filtered_count: u32,
+    // Why they were removed, in the provider's words
+    pub filtered_reasons: Vec<String>,
+}
+
+impl From<Vec<ProviderImage>> for ProviderOutput {
+    fn from(images: Vec<ProviderImage>) -> Self {
+        Self {
+            images,
+            ..Default::default()
+        }
+    }
+}
+
+impl ProviderOutput {
+    // Fail when nothing is left to return, with the reasons of the filters
+    pub fn check_filtered(self) -> Result<Self, ProviderError> {
+        if !self.images.is_empty() {
+            return Ok(self);
+        }
+        let reasons = if self.filtered_reasons.is_empty() {
+            "This might be due to the image not passing the provider's safety review.".to_string()
+        } else {
+            self.filtered_reasons.join(" ")
+        };
+        Err(ProviderError::SafetyFiltered(format!(
+            "No images were generated. {}",
+            reasons
+        )))
+    }
+}
+
+// A backend that turns a prompt into images
+pub trait ImageProvider: fmt::Debug + Send + Sync {
+    // The name used to select this provider, e.g. "imagen"
+    fn name(&self) -> &'static str;
+
+    // The models this provider knows about, the default model comes first
+    fn models(&self) -> &[ModelInfo];
+
+    fn generate<'a>(&'a self, request: &'a GenerationRequest) -> ProviderFuture<'a>;
+
+    // Whether the generated images already have the requested output format
+    // and quality, so that they are saved as they are
+    fn encodes_output(&self, _request: &GenerationRequest) -> bool {
+        false
+    }
+
+    // Only called for models with editing support
+    fn edit<'a>(&'a self, _request: &'a EditRequest) -> ProviderFuture<'a> {
+        Box::pin(async move {
+            Err(ProviderError::InvalidParameter(format!(
+                "Provider {} does not support editing",
+                self.name()
+            )))
+        })
+    }
+}
+
+// The HTTP client shared by the providers, with the timeouts from
+// CONNECT_TIMEOUT_SECONDS and REQUEST_TIMEOUT_SECONDS
+fn http_client() -> Result<reqwest::Client, String> {
+    let seconds = |name: &str, default: u64| match env::var(name) {
+        Ok(value) if !value.trim().is_empty() => value
+            .trim()
+            .parse::<u64>()
+            .ok()
+            .filter(|seconds| *seconds > 0)
+            .map(Duration::from_secs)
+            .ok_or_else(|| format!("Invalid {}: {}", name, value)),
+        _ => Ok(Duration::from_secs(default)),
+    };
+    let connect_timeout = seconds("CONNECT_TIMEOUT_SECONDS", DEFAULT_CONNECT_TIMEOUT_SECONDS)?;
+    let timeout = seconds("REQUEST_TIMEOUT_SECONDS", DEFAULT_REQUEST_TIMEOUT_SECONDS)?;
+    info!(
+        ?connect_timeout,
+        ?timeout,
+        "Provider request timeouts configured."
+    );
+    reqwest::Client::builder()
+        .connect_timeout(connect_timeout)
+        .timeout(timeout)
+        .build()
+        .map_err(|e| format!("Failed to create HTTP client: {}", e))
+}
+
+// The providers that are available in this process, keyed by name
+#[derive(Debug, Clone)]
+pub struct ProviderRegistry {
+    providers: HashMap<&'static str, Arc<dyn ImageProvider>>,
+    default_provider: String,
+}
+
+impl ProviderRegistry {
+    // Build every provider whose credentials are present in the environment.
+    // The mock provider is always available since it needs no network access.
+    pub fn from_env() -> Result<Self, String> {
+        let client = http_client()?;
+        let mut providers: HashMap<&'static str, Arc<dyn ImageProvider>> = HashMap::new();
+
+        let mut register = |provider: Arc<dyn ImageProvider>| {
+            info!(
+                provider = provider.name(),
+                default_model = provider.models()[0].id,
+                "Registered image provider."
+            );
+            providers.insert(provider.name(), provider);
+        };
+
+        match env::var("GEMINI_API_KEY") {
+            Ok(api_key) => {
+                let base_url = env::var("BASE_URL").unwrap_or_else(|_| GOOGLE_BASE_URL.to_string());
+                register(Arc::new(ImagenProvider::new(
+                    client.clone(),
+                    api_key.clone(),
+                    base_url.clone(),
+                    env::var("IMAGEN_MODEL").ok(),
+                )));
+                register(Arc::new(GeminiProvider::new(
+                    client.clone(),
+                    api_key,
+                    base_url,
+                    env::var("GEMINI_IMAGE_MODEL").ok(),
+                )));
+            }
+            Err(_) => {
+                warn!("GEMINI_API_KEY is not set. The imagen and gemini providers are disabled.")
+            }
+        }
+
+        if let Ok(api_key) = env::var("OPENAI_API_KEY") {
+            register(Arc::new(OpenAiProvider::new(
+                client,
+                api_key,
+                env::var("OPENAI_BASE_URL").ok(),
+                env::var("OPENAI_IMAGE_MODEL").ok(),
+            )));
+        }
+
+        register(Arc::new(MockProvider::new()));
+
+        let default_provider =
+            env::var("IMAGE_PROVIDER").unwrap_or_else(|_| ImagenProvider::NAME.to_string());
+
+        Ok(Self {
+            providers,
+            default_provider,
+        })
+    }
+
+    pub fn default_provider(&self) -> &str {
+        &self.default_provider
+    }
+
+    // Look up a provider by name, falling back to the configured default
+    pub fn get(&self, name: Option<&str>) -> Result<Arc<dyn ImageProvider>, ProviderError> {
+        let name = name.unwrap_or(&self.default_provider);
+        if let Some(provider) = self.providers.get(name) {
+            return Ok(provider.clone());
+        }
+        // Providers of this crate are only registered when their key is set
+        let variable = match name {
+            ImagenProvider::NAME | GeminiProvider::NAME => Some("GEMINI_API_KEY"),
+            OpenAiProvider::NAME => Some("OPENAI_API_KEY"),
+            _ => None,
+        };
+        Err(match variable {
+            Some(variable) => ProviderError::MissingApiKey {
+                provider: name.to_string(),
+                variable,
+            },
+            None => ProviderError::InvalidParameter(format!(
+                "Image provider \"{}\" is not available, configured providers are: {}",
+                name,
+                self.names().join(", ")
+            )),
+        })
+    }
+
+    // Pick the provider and model for a request. A model without a provider
+    // is looked up across all providers, by full id or alias.
+    pub fn resolve(
+        &self,
+        provider: Option<&str>,
+        model: Option<&str>,
+    ) -> Result<(Arc<dyn ImageProvider>, ModelInfo), ProviderError> {
+        let provider = match (provider, model) {
+            (None, Some(model)) => self
+                .names()
+                .into_iter()
+                .map(|name| self.providers[name].clone())
+                .find(|provider| provider.models().iter().any(|m| m.matches(model)))
+                .ok_or_else(|| {
+                    ProviderError::InvalidParameter(format!(
+                        "Model \"{}\" is not available, use list_models to see the configured models",
+                        model
+                    ))
+                })?,
+            (provider, _) => self.get(provider)?,
+        };
+
+        let models = provider.models();
+        let model = match model {
+            Some(model) => models.iter().find(|m| m.matches(model)).ok_or_else(|| {
+                ProviderError::InvalidParameter(format!(
+                    "Model \"{}\" is not available for provider \"{}\", supported models are: {}",
+                    model,
+                    provider.name(),
+                    models
+                        .iter()
+                        .map(|m| m.id.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ))
+            })?,
+            None => &models[0],
+        }
+        .clone();
+
+        Ok((provider, model))
+    }
+
+    // The names of all registered providers, sorted for stable output
+    pub fn names(&self) -> Vec<&'static str> {
+        let mut names: Vec<_> = self.providers.keys().copied().collect();
+        names.sort_unstable();
+        names
+    }
+}
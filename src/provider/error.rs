@@ -0,0 +1,520 @@
+use std::fmt;
+use std::time::Duration;
+
+use serde::Serialize;
+use serde_json::Value;
+
+use super::RetryPolicy;
+
+// Everything that can go wrong between a tool call and the saved images. The
+// code tells clients whether to change the request or to try again later.
+#[derive(Debug)]
+pub enum ProviderError {
+    // The provider is known but its API key is not set
+    MissingApiKey {
+        provider: String,
+        variable: &'static str,
+    },
+    // The request does not fit the model or refers to a missing image
+    InvalidParameter(String),
+    // The API answered with an error status
+    Http {
+        status: u16,
+        message: String,
+    },
+    // Rate limit or quota of the API key reached, `retry_after` is how long
+    // the API asked to wait
+    QuotaExceeded {
+        message: String,
+        retry_after: Option<Duration>,
+    },
+    // The API key is invalid or may not use the model
+    PermissionDenied(String),
+    // The prompt or the images were blocked by the provider's safety filters
+    SafetyFiltered(String),
+    // The request could not be sent or the response could not be read
+    Network(String),
+    // The provider did not answer within REQUEST_TIMEOUT_SECONDS
+    Timeout(String),
+    // The client cancelled the tool call
+    Cancelled,
+    // The response or an image in it could not be decoded
+    Decode(String),
+    Io(std::io::Error),
+    Internal(String),
+}
+
+// The error as it is sent to MCP clients
+#[derive(Debug, Serialize)]
+pub struct ErrorDetails {
+    pub code: &'static str,
+    pub message: String,
+    pub retryable: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_after_seconds: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hint: Option<String>,
+    // How often the provider was called before giving up, see RetryPolicy
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attempts: Option<u32>,
+}
+
+// What Google's APIs put in the body of an error response:
+// {"error": {"code": 403, "message": "...", "status": "PERMISSION_DENIED", "details": [...]}}
+// OpenAI uses the same "error" object with a "type" instead of a status.
+struct ApiError {
+    message: String,
+    status: Option<String>,
+    // The "reason" of a google.rpc.ErrorInfo detail, e.g. "API_KEY_INVALID"
+    reason: Option<String>,
+    // The "retryDelay" of a google.rpc.RetryInfo detail
+    retry_delay: Option<Duration>,
+    // The quotas of a google.rpc.QuotaFailure detail
+    quotas: Vec<String>,
+}
+
+impl ApiError {
+    fn parse(body: &str) -> Option<Self> {
+        let envelope: Value = serde_json::from_str(body).ok()?;
+        let error = envelope.get("error")?;
+        let text =
+            |value: &Value, key: &str| value.get(key).and_then(Value::as_str).map(str::to_string);
+        let mut api_error = ApiError {
+            message: text(error, "message").unwrap_or_default(),
+            status: text(error, "status").or_else(|| text(error, "type")),
+            reason: None,
+            retry_delay: None,
+            quotas: Vec::new(),
+        };
+        let details = error.get("details").and_then(Value::as_array);
+        for detail in details.into_iter().flatten() {
+            let kind = detail
+                .get("@type")
+                .and_then(Value::as_str)
+                .unwrap_or_default();
+            if kind.ends_with("google.rpc.ErrorInfo") {
+                api_error.reason = text(detail, "reason");
+            } else if kind.ends_with("google.rpc.RetryInfo") {
+                api_error.retry_delay = text(detail, "retryDelay")
+                    .and_then(|delay| parse_seconds(delay.trim_end_matches('s')));
+            } else if kind.ends_with("google.rpc.QuotaFailure") {
+                let violations = detail.get("violations").and_then(Value::as_array);
+                api_error
+                    .quotas
+                    .extend(violations.into_iter().flatten().filter_map(|violation| {
+                        text(violation, "quotaId").or_else(|| text(violation, "quotaMetric"))
+                    }));
+            }
+        }
+        Some(api_error)
+    }
+
+    // "STATUS: message", or the message alone
+    fn describe(&self) -> String {
+        match &self.status {
+            Some(status) if !self.message.is_empty() => format!("{}: {}", status, self.message),
+            Some(status) => status.clone(),
+            None => self.message.clone(),
+        }
+    }
+}
+
+// Seconds as in "7" or "1.5"
+fn parse_seconds(value: &str) -> Option<Duration> {
+    value
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+}
+
+// The Retry-After header holds either seconds or an HTTP date
+fn retry_after(headers: &reqwest::header::HeaderMap) -> Option<Duration> {
+    let value = headers
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim();
+    parse_seconds(value).or_else(|| {
+        let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+        (date.with_timezone(&chrono::Utc) - chrono::Utc::now())
+            .to_std()
+            .ok()
+    })
+}
+
+impl ProviderError {
+    // The error for a response with an unsuccessful status. The error object
+    // in the body is used when there is one, the raw body otherwise.
+    pub fn from_response(
+        status: reqwest::StatusCode,
+        headers: &reqwest::header::HeaderMap,
+        body: &str,
+    ) -> Self {
+        let api_error = ApiError::parse(body);
+        let message = match &api_error {
+            Some(api_error) if !api_error.message.is_empty() => api_error.describe(),
+            _ => body.trim().to_string(),
+        };
+        // OpenAI reports blocked prompts as bad requests
+        if body.contains("content_policy_violation") || body.contains("moderation_blocked") {
+            return ProviderError::SafetyFiltered(message);
+        }
+        let api_status = api_error
+            .as_ref()
+            .and_then(|api_error| api_error.status.as_deref());
+        let reason = api_error
+            .as_ref()
+            .and_then(|api_error| api_error.reason.as_deref());
+        match (status, api_status) {
+            (reqwest::StatusCode::TOO_MANY_REQUESTS, _) | (_, Some("RESOURCE_EXHAUSTED")) => {
+                let mut message = message;
+                if let Some(api_error) = &api_error
+                    && !api_error.quotas.is_empty()
+                {
+                    message.push_str(&format!(" (quota: {})", api_error.quotas.join(", ")));
+                }
+                ProviderError::QuotaExceeded {
+                    message,
+                    retry_after: retry_after(headers)
+                        .or_else(|| api_error.and_then(|api_error| api_error.retry_delay)),
+                }
+            }
+            (reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN, _)
+            | (_, Some("PERMISSION_DENIED" | "UNAUTHENTICATED")) => {
+                ProviderError::PermissionDenied(message)
+            }
+            // Google answers an invalid key with a bad request
+            _ if reason == Some("API_KEY_INVALID") => ProviderError::PermissionDenied(message),
+            _ => ProviderError::Http {
+                status: status.as_u16(),
+                message,
+            },
+        }
+    }
+
+    // A machine readable code, stable across releases
+    pub fn code(&self) -> &'static str {
+        match self {
+            ProviderError::MissingApiKey { .. } => "missing_api_key",
+            ProviderError::InvalidParameter(_) => "invalid_parameter",
+            ProviderError::Http { .. } => "http_error",
+            ProviderError::QuotaExceeded { .. } => "quota_exceeded",
+            ProviderError::PermissionDenied(_) => "permission_denied",
+            ProviderError::SafetyFiltered(_) => "safety_filtered",
+            ProviderError::Network(_) => "network_error",
+            ProviderError::Timeout(_) => "timeout",
+            ProviderError::Cancelled => "cancelled",
+            ProviderError::Decode(_) => "decode_error",
+            ProviderError::Io(_) => "io_error",
+            ProviderError::Internal(_) => "internal_error",
+        }
+    }
+
+    // What the client should do about it
+    pub fn hint(&self) -> Option<String> {
+        match self {
+            ProviderError::MissingApiKey { variable, .. } => Some(format!(
+                "Set {} in the server configuration, or use list_models to pick a configured provider",
+                variable
+            )),
+            ProviderError::InvalidParameter(_) => {
+                Some("Fix the parameters and call the tool again".to_string())
+            }
+            ProviderError::QuotaExceeded {
+                retry_after: Some(retry_after),
+                ..
+            } => Some(format!(
+                "Wait {} seconds and try again",
+                retry_after.as_secs_f64().ceil()
+            )),
+            ProviderError::QuotaExceeded { .. } => {
+                Some("Wait a while and try again, or check the quota of the API key".to_string())
+            }
+            ProviderError::PermissionDenied(_) => Some(
+                "Check that the API key is valid and may use the model, e.g. Imagen needs a billing account"
+                    .to_string(),
+            ),
+            ProviderError::SafetyFiltered(_) => Some(
+                "Rephrase the prompt, e.g. leave out real people, brands or violent content"
+                    .to_string(),
+            ),
+            ProviderError::Network(_) => Some("Try again".to_string()),
+            ProviderError::Timeout(_) => Some(
+                "Try again, with fewer images or a faster model if it keeps timing out".to_string(),
+            ),
+            ProviderError::Http { status, .. } if *status >= 500 => {
+                Some("The provider has problems, try again later".to_string())
+            }
+            _ => None,
+        }
+    }
+
+    // How long the API asked to wait before the next request
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            ProviderError::QuotaExceeded { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+
+    // `retryable` says whether the retry policy repeats such errors, so that
+    // clients agree with what the server itself retries
+    pub fn details(&self, retry_policy: &RetryPolicy) -> ErrorDetails {
+        ErrorDetails {
+            code: self.code(),
+            message: self.to_string(),
+            retryable: retry_policy.is_retryable(self),
+            retry_after_seconds: self
+                .retry_after()
+                .map(|delay| delay.as_secs_f64().ceil() as u64),
+            hint: self.hint(),
+            attempts: None,
+        }
+    }
+}
+
+impl fmt::Display for ProviderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProviderError::MissingApiKey { provider, variable } => write!(
+                f,
+                "Image provider \"{}\" is not available, {} is not set",
+                provider, variable
+            ),
+            ProviderError::Http { status, message } => {
+                write!(f, "The API returned HTTP {}: {}", status, message)
+            }
+            ProviderError::QuotaExceeded { message, .. } => {
+                write!(f, "The API quota is exceeded: {}", message)
+            }
+            ProviderError::PermissionDenied(message) => {
+                write!(f, "The API refused the API key: {}", message)
+            }
+            ProviderError::SafetyFiltered(message) => write!(f, "{}", message),
+            ProviderError::Timeout(message) => {
+                write!(f, "The provider did not answer in time: {}", message)
+            }
+            ProviderError::Cancelled => write!(f, "The request was cancelled"),
+            ProviderError::Io(e) => write!(f, "{}", e),
+            ProviderError::InvalidParameter(message)
+            | ProviderError::Network(message)
+            | ProviderError::Decode(message)
+            | ProviderError::Internal(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for ProviderError {}
+
+impl From<reqwest::Error> for ProviderError {
+    fn from(e: reqwest::Error) -> Self {
+        // The URL can contain the API key
+        let timeout = e.is_timeout();
+        let message = e.without_url().to_string();
+        if timeout {
+            ProviderError::Timeout(message)
+        } else {
+            ProviderError::Network(message)
+        }
+    }
+}
+
+impl From<serde_json::Error> for ProviderError {
+    fn from(e: serde_json::Error) -> Self {
+        ProviderError::Decode(e.to_string())
+    }
+}
+
+impl From<base64::DecodeError> for ProviderError {
+    fn from(e: base64::DecodeError) -> Self {
+        ProviderError::Decode(format!("Failed to decode base64 image: {}", e))
+    }
+}
+
+impl From<image::ImageError> for ProviderError {
+    fn from(e: image::ImageError) -> Self {
+        ProviderError::Decode(format!("Failed to process image: {}", e))
+    }
+}
+
+impl From<std::io::Error> for ProviderError {
+    fn from(e: std::io::Error) -> Self {
+        ProviderError::Io(e)
+    }
+}
+
+impl From<tokio::task::JoinError> for ProviderError {
+    fn from(e: tokio::task::JoinError) -> Self {
+        ProviderError::Internal(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::StatusCode;
+    use reqwest::header::{HeaderMap, HeaderValue, RETRY_AFTER};
+
+    fn headers(retry_after: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from_str(retry_after).unwrap());
+        headers
+    }
+
+    const QUOTA_BODY: &str = r#"{
+        "error": {
+            "code": 429,
+            "message": "Quota exceeded for aiplatform.googleapis.com/online_prediction_requests_per_base_model.",
+            "status": "RESOURCE_EXHAUSTED",
+            "details": [
+                {
+                    "@type": "type.googleapis.com/google.rpc.QuotaFailure",
+                    "violations": [
+                        {"quotaMetric": "generativelanguage.googleapis.com/generate_requests", "quotaId": "GenerateRequestsPerMinutePerProject"}
+                    ]
+                },
+                {
+                    "@type": "type.googleapis.com/google.rpc.RetryInfo",
+                    "retryDelay": "17s"
+                }
+            ]
+        }
+    }"#;
+
+    #[test]
+    fn parses_google_error_details() {
+        let api_error = ApiError::parse(QUOTA_BODY).unwrap();
+        assert_eq!(api_error.status.as_deref(), Some("RESOURCE_EXHAUSTED"));
+        assert_eq!(api_error.retry_delay, Some(Duration::from_secs(17)));
+        assert_eq!(api_error.quotas, ["GenerateRequestsPerMinutePerProject"]);
+        assert!(
+            api_error
+                .describe()
+                .starts_with("RESOURCE_EXHAUSTED: Quota exceeded")
+        );
+    }
+
+    #[test]
+    fn parses_error_info_reason() {
+        let body = r#"{"error": {"code": 400, "message": "API key not valid.", "status": "INVALID_ARGUMENT",
+            "details": [{"@type": "type.googleapis.com/google.rpc.ErrorInfo", "reason": "API_KEY_INVALID", "domain": "googleapis.com"}]}}"#;
+        let api_error = ApiError::parse(body).unwrap();
+        assert_eq!(api_error.reason.as_deref(), Some("API_KEY_INVALID"));
+    }
+
+    #[test]
+    fn parses_openai_error_type() {
+        let body = r#"{"error": {"message": "Incorrect API key provided.", "type": "invalid_request_error"}}"#;
+        let api_error = ApiError::parse(body).unwrap();
+        assert_eq!(
+            api_error.describe(),
+            "invalid_request_error: Incorrect API key provided."
+        );
+    }
+
+    #[test]
+    fn too_many_requests_is_quota_exceeded() {
+        let error = ProviderError::from_response(
+            StatusCode::TOO_MANY_REQUESTS,
+            &HeaderMap::new(),
+            QUOTA_BODY,
+        );
+        match error {
+            ProviderError::QuotaExceeded {
+                message,
+                retry_after,
+            } => {
+                assert!(message.contains("(quota: GenerateRequestsPerMinutePerProject)"));
+                assert_eq!(retry_after, Some(Duration::from_secs(17)));
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn resource_exhausted_is_quota_exceeded_whatever_the_status() {
+        let error =
+            ProviderError::from_response(StatusCode::BAD_REQUEST, &HeaderMap::new(), QUOTA_BODY);
+        assert_eq!(error.code(), "quota_exceeded");
+    }
+
+    #[test]
+    fn forbidden_is_permission_denied() {
+        let body = r#"{"error": {"code": 403, "message": "Imagen API is only accessible to billed users at this time.", "status": "PERMISSION_DENIED"}}"#;
+        let error = ProviderError::from_response(StatusCode::FORBIDDEN, &HeaderMap::new(), body);
+        match error {
+            ProviderError::PermissionDenied(message) => assert_eq!(
+                message,
+                "PERMISSION_DENIED: Imagen API is only accessible to billed users at this time."
+            ),
+            other => panic!("unexpected error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn invalid_api_key_is_permission_denied() {
+        let body = r#"{"error": {"code": 400, "message": "API key not valid. Please pass a valid API key.", "status": "INVALID_ARGUMENT",
+            "details": [{"@type": "type.googleapis.com/google.rpc.ErrorInfo", "reason": "API_KEY_INVALID"}]}}"#;
+        let error = ProviderError::from_response(StatusCode::BAD_REQUEST, &HeaderMap::new(), body);
+        assert_eq!(error.code(), "permission_denied");
+    }
+
+    #[test]
+    fn body_without_json_is_kept_as_message() {
+        let error = ProviderError::from_response(
+            StatusCode::BAD_GATEWAY,
+            &HeaderMap::new(),
+            "<html>502 Bad Gateway</html>\n",
+        );
+        match error {
+            ProviderError::Http { status, message } => {
+                assert_eq!(status, 502);
+                assert_eq!(message, "<html>502 Bad Gateway</html>");
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn retry_after_in_seconds() {
+        let error = ProviderError::from_response(
+            StatusCode::TOO_MANY_REQUESTS,
+            &headers("120"),
+            "Too Many Requests",
+        );
+        assert_eq!(error.retry_after(), Some(Duration::from_secs(120)));
+        assert_eq!(error.code(), "quota_exceeded");
+    }
+
+    #[test]
+    fn retry_after_header_wins_over_retry_info() {
+        let error =
+            ProviderError::from_response(StatusCode::TOO_MANY_REQUESTS, &headers("5"), QUOTA_BODY);
+        assert_eq!(error.retry_after(), Some(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn retry_after_as_http_date() {
+        let date = (chrono::Utc::now() + chrono::Duration::seconds(90)).to_rfc2822();
+        let error = ProviderError::from_response(
+            StatusCode::TOO_MANY_REQUESTS,
+            &headers(&date),
+            "Too Many Requests",
+        );
+        // The date has whole seconds and some time passes before it is read
+        let retry_after = error.retry_after().unwrap();
+        assert!(retry_after > Duration::from_secs(85), "{:?}", retry_after);
+        assert!(retry_after <= Duration::from_secs(90), "{:?}", retry_after);
+    }
+
+    #[test]
+    fn retry_after_in_the_past_is_ignored() {
+        let error = ProviderError::from_response(
+            StatusCode::TOO_MANY_REQUESTS,
+            &headers("Wed, 21 Oct 2015 07:28:00 GMT"),
+            "Too Many Requests",
+        );
+        assert_eq!(error.retry_after(), None);
+    }
+}
@@ -0,0 +1,372 @@
+use base64::Engine as _;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, instrument, warn};
+
+use super::{
+    EditRequest, GenerationRequest, ImageProvider, ModelCapabilities, ModelInfo, ProviderError,
+    ProviderFuture, ProviderImage, ProviderOutput, model_list, with_negative_prompt,
+};
+
+const GEMINI_CAPABILITIES: ModelCapabilities = ModelCapabilities {
+    aspect_ratios: &[
+        "1:1", "2:3", "3:2", "3:4", "4:3", "4:5", "5:4", "9:16", "16:9", "21:9",
+    ],
+    max_sample_count: 4,
+    negative_prompt: true,
+    // The generation config has a seed but no guidance or prompt rewriting
+    seed: true,
+    guidance_scale: false,
+    enhance_prompt: false,
+    editing: true,
+};
+
+const MODELS: &[(&str, Option<&str>, ModelCapabilities)] = &[
+    ("gemini-2.5-flash-image", None, GEMINI_CAPABILITIES),
+    (
+        "gemini-2.0-flash-preview-image-generation",
+        None,
+        // The preview model has no image config and always renders squares
+        ModelCapabilities {
+            aspect_ratios: &["1:1"],
+            ..GEMINI_CAPABILITIES
+        },
+    ),
+];
+
+// Request and response structures for the Gemini generateContent API
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GenerateContentRequest {
+    contents: Vec<GeminiContent>,
+    generation_config: GenerationConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct GeminiContent {
+    #[serde(default)]
+    parts: Vec<GeminiPart>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GeminiPart {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    inline_data: Option<InlineData>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct InlineData {
+    mime_type: String,
+    data: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GenerationConfig {
+    response_modalities: Vec<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    image_config: Option<ImageConfig>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ImageConfig {
+    aspect_ratio: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GenerateContentResponse {
+    candidates: Option<Vec<GeminiCandidate>>,
+    model_version: Option<String>,
+    // Set instead of candidates when the prompt itself was blocked
+    prompt_feedback: Option<PromptFeedback>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GeminiCandidate {
+    content: Option<GeminiContent>,
+    // e.g. "STOP", or "IMAGE_SAFETY" when the image was filtered
+    finish_reason: Option<String>,
+    finish_message: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PromptFeedback {
+    block_reason: Option<String>,
+    block_reason_message: Option<String>,
+}
+
+// Gemini models with native image output, served through `:generateContent`
+#[derive(Debug)]
+pub struct GeminiProvider {
+    client: reqwest::Client,
+    api_key: String,
+    base_url: String,
+    models: Vec<ModelInfo>,
+}
+
+impl GeminiProvider {
+    pub const NAME: &'static str = "gemini";
+
+    pub const DEFAULT_MODEL: &'static str = "gemini-2.5-flash-image";
+
+    pub fn new(
+        client: reqwest::Client,
+        api_key: String,
+        base_url: String,
+        model: Option<String>,
+    ) -> Self {
+        Self {
+            client,
+            api_key,
+            base_url,
+            models: model_list(
+                MODELS,
+                model.as_deref().unwrap_or(Self::DEFAULT_MODEL),
+                GEMINI_CAPABILITIES,
+            ),
+        }
+    }
+
+    fn text_part(text: String) -> GeminiPart {
+        GeminiPart {
+            text: Some(text),
+            inline_data: None,
+        }
+    }
+
+    fn image_part(image: &ProviderImage) -> GeminiPart {
+        GeminiPart {
+            text: None,
+            inline_data: Some(InlineData {
+                mime_type: image.mime_type.clone(),
+                data: base64::engine::general_purpose::STANDARD.encode(&image.bytes),
+            }),
+        }
+    }
+
+    // generateContent returns a single candidate, so one call is made per sample
+    async fn generate_samples(
+        &self,
+        model: &str,
+        parts: Vec<GeminiPart>,
+        sample_count: u32,
+        seed: Option<u32>,
+        image_config: Option<ImageConfig>,
+    ) -> Result<ProviderOutput, ProviderError> {
+        let url = format!(
+            "{}/v1beta/models/{}:generateContent?key={}",
+            self.base_url, model, self.api_key
+        );
+
+        let mut output = ProviderOutput::default();
+        for sample in 0..sample_count {
+            let body = GenerateContentRequest {
+                contents: vec![GeminiContent {
+                    parts: parts.clone(),
+                }],
+                generation_config: GenerationConfig {
+                    response_modalities: vec!["TEXT", "IMAGE"],
+                    // Offset the seed so that every sample differs but stays reproducible
+                    seed: seed.map(|seed| seed.wrapping_add(sample)),
+                    image_config: image_config.clone(),
+                },
+            };
+            info!(
+                sample,
+                text = ?body.contents[0].parts.iter().filter_map(|part| part.text.as_deref()).collect::<Vec<_>>(),
+                "Sending request to Gemini."
+            );
+
+            let response = match self.client.post(&url).json(&body).send().await {
+                Ok(resp) => resp,
+                Err(e) => {
+                    let e = e.without_url();
+                    error!("Failed to send request to Gemini: {}", e);
+                    return Err(e.into());
+                }
+            };
+            let status = response.status();
+            let headers = response.headers().clone();
+            let response_text = response.text().await?;
+            if !status.is_success() {
+                error!(%status, response_body = %response_text, "Gemini returned an error.");
+                return Err(ProviderError::from_response(
+                    status,
+                    &headers,
+                    &response_text,
+                ));
+            }
+
+            let response: GenerateContentResponse = match serde_json::from_str(&response_text) {
+                Ok(response) => response,
+                Err(e) => {
+                    error!(
+                        response_body = %response_text,
+                        "Failed to parse Gemini response: {}",
+                        e
+                    );
+                    return Err(ProviderError::Decode(format!(
+                        "Failed to parse Gemini response: {}
+The response was: {}",
+                        e, response_text
+                    )));
+                }
+            };
+
+            let candidates = response.candidates.unwrap_or_default();
+            let filtered_reason = Self::filtered_reason(response.prompt_feedback, &candidates);
+            let parts = candidates
+                .into_iter()
+                .filter_map(|candidate| candidate.content)
+                .flat_map(|content| content.parts)
+                .collect::<Vec<_>>();
+
+            // The model often comments on the image, keep that with the image
+            let text = parts
+                .iter()
+                .filter_map(|part| part.text.as_deref())
+                .collect::<Vec<_>>()
+                .join("\n");
+            let mut details = serde_json::Map::new();
+            if !text.is_empty() {
+                details.insert("text".to_string(), text.clone().into());
+            }
+            if let Some(model_version) = &response.model_version {
+                details.insert("model_version".to_string(), model_version.clone().into());
+            }
+
+            let image_count = output.images.len();
+            for part in parts {
+                if let Some(inline_data) = part.inline_data {
+                    let bytes =
+                        match base64::engine::general_purpose::STANDARD.decode(&inline_data.data) {
+                            Ok(data) => data,
+                            Err(e) => {
+                                error!("Failed to decode base64 image: {}", e);
+                                return Err(e.into());
+                            }
+                        };
+                    output.images.push(ProviderImage {
+                        bytes,
+                        mime_type: inline_data.mime_type,
+                        response: (!details.is_empty())
+                            .then(|| serde_json::Value::Object(details.clone())),
+                        safety_attributes: Vec::new(),
+                    });
+                }
+            }
+
+            // A sample without an image was blocked, or the model only answered
+            // in text, which usually explains why
+            if output.images.len() == image_count {
+                let reason = filtered_reason.or((!text.is_empty()).then_some(text));
+                warn!(sample, ?reason, "Gemini returned no image.");
+                output.filtered_count += 1;
+                output.filtered_reasons.extend(reason);
+            }
+        }
+
+        output.check_filtered()
+    }
+
+    // Why the prompt or the image of a response was blocked, if it was
+    fn filtered_reason(
+        prompt_feedback: Option<PromptFeedback>,
+        candidates: &[GeminiCandidate],
+    ) -> Option<String> {
+        if let Some(feedback) = prompt_feedback
+            && let Some(block_reason) = feedback.block_reason
+        {
+            return Some(match feedback.block_reason_message {
+                Some(message) => format!("The prompt was blocked ({}): {}", block_reason, message),
+                None => format!("The prompt was blocked ({}).", block_reason),
+            });
+        }
+        candidates.iter().find_map(|candidate| {
+            let finish_reason = candidate.finish_reason.as_deref()?;
+            if finish_reason == "STOP" {
+                return None;
+            }
+            Some(match &candidate.finish_message {
+                Some(message) => format!("The image was blocked ({}): {}", finish_reason, message),
+                None => format!("The image was blocked ({}).", finish_reason),
+            })
+        })
+    }
+
+    #[instrument(skip(self, request), fields(model = %request.model, prompt_length = request.prompt.len()))]
+    async fn generate_content(
+        &self,
+        request: &GenerationRequest,
+    ) -> Result<ProviderOutput, ProviderError> {
+        // generateContent has no negative prompt parameter, so it is folded
+        // into the prompt text instead
+        let text = with_negative_prompt(request.prompt.clone(), request.negative_prompt.as_deref());
+        self.generate_samples(
+            &request.model,
+            vec![Self::text_part(text)],
+            request.sample_count,
+            request.seed,
+            request
+                .aspect_ratio
+                .clone()
+                .map(|aspect_ratio| ImageConfig { aspect_ratio }),
+        )
+        .await
+    }
+
+    // Gemini edits images from a text instruction, the mask is passed as a
+    // second image that the instruction refers to
+    #[instrument(skip(self, request), fields(model = %request.model, mode = request.mode.as_str()))]
+    async fn edit_content(&self, request: &EditRequest) -> Result<ProviderOutput, ProviderError> {
+        let instruction = request
+            .mode
+            .instruction(&request.prompt, request.mask.is_some());
+        let mut parts = vec![
+            Self::text_part(with_negative_prompt(
+                instruction,
+                request.negative_prompt.as_deref(),
+            )),
+            Self::image_part(&request.image),
+        ];
+        if let Some(mask) = &request.mask {
+            parts.push(Self::text_part("Mask image:".to_string()));
+            parts.push(Self::image_part(mask));
+        }
+        self.generate_samples(
+            &request.model,
+            parts,
+            request.sample_count,
+            request.seed,
+            None,
+        )
+        .await
+    }
+}
+
+impl ImageProvider for GeminiProvider {
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
+
+    fn models(&self) -> &[ModelInfo] {
+        &self.models
+    }
+
+    fn generate<'a>(&'a self, request: &'a GenerationRequest) -> ProviderFuture<'a> {
+        Box::pin(self.generate_content(request))
+    }
+
+    fn edit<'a>(&'a self, request: &'a EditRequest) -> ProviderFuture<'a> {
+        Box::pin(self.edit_content(request))
+    }
+}
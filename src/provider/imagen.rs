@@ -0,0 +1,285 @@
+use base64::Engine as _;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, instrument, warn};
+
+use super::{
+    COMMON_ASPECT_RATIOS, GenerationRequest, ImageProvider, ModelCapabilities, ModelInfo,
+    ProviderError, ProviderFuture, ProviderImage, ProviderOutput, SafetyAttribute, model_list,
+};
+
+// Imagen on the Gemini API ignores negative prompts and has no edit endpoint.
+const IMAGEN_CAPABILITIES: ModelCapabilities = ModelCapabilities {
+    aspect_ratios: COMMON_ASPECT_RATIOS,
+    max_sample_count: 4,
+    negative_prompt: false,
+    seed: true,
+    guidance_scale: true,
+    enhance_prompt: true,
+    editing: false,
+};
+
+const MODELS: &[(&str, Option<&str>, ModelCapabilities)] = &[
+    (
+        "imagen-3.0-generate-002",
+        Some("imagen-3"),
+        IMAGEN_CAPABILITIES,
+    ),
+    (
+        "imagen-4.0-generate-001",
+        Some("imagen-4"),
+        IMAGEN_CAPABILITIES,
+    ),
+    (
+        "imagen-4.0-fast-generate-001",
+        Some("imagen-4-fast"),
+        IMAGEN_CAPABILITIES,
+    ),
+    (
+        "imagen-4.0-ultra-generate-001",
+        Some("imagen-4-ultra"),
+        ModelCapabilities {
+            max_sample_count: 1,
+            ..IMAGEN_CAPABILITIES
+        },
+    ),
+];
+
+// Request and response structures for the Imagen predict API
+#[derive(Debug, Serialize)]
+struct ImagenRequest {
+    instances: Vec<ImagenInstance>,
+    parameters: ImagenParameters,
+}
+
+#[derive(Debug, Serialize)]
+struct ImagenInstance {
+    prompt: String,
+}
+
+#[derive(Debug, Serialize)]
+struct ImagenParameters {
+    #[serde(rename = "sampleCount")]
+    sample_count: u32,
+    #[serde(rename = "aspectRatio")]
+    aspect_ratio: Option<String>,
+    #[serde(rename = "negativePrompt", skip_serializing_if = "Option::is_none")]
+    negative_prompt: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u32>,
+    #[serde(rename = "addWatermark", skip_serializing_if = "Option::is_none")]
+    add_watermark: Option<bool>,
+    #[serde(rename = "enhancePrompt", skip_serializing_if = "Option::is_none")]
+    enhance_prompt: Option<bool>,
+    #[serde(rename = "guidanceScale", skip_serializing_if = "Option::is_none")]
+    guidance_scale: Option<f32>,
+    // Say why samples were filtered and how each image was rated
+    #[serde(rename = "includeRaiReason")]
+    include_rai_reason: bool,
+    #[serde(rename = "includeSafetyAttributes")]
+    include_safety_attributes: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct ImagenResponse {
+    predictions: Option<Vec<ImagenPrediction>>,
+}
+
+// An image, or in place of the filtered images the reason they were removed
+#[derive(Debug, Deserialize)]
+struct ImagenPrediction {
+    #[serde(rename = "mimeType")]
+    mime_type: Option<String>,
+    #[serde(rename = "bytesBase64Encoded")]
+    bytes_base64_encoded: Option<String>,
+    #[serde(rename = "raiFilteredReason")]
+    rai_filtered_reason: Option<String>,
+    #[serde(rename = "safetyAttributes")]
+    safety_attributes: Option<ImagenSafetyAttributes>,
+    // Other fields, such as the enhanced prompt
+    #[serde(flatten)]
+    other: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ImagenSafetyAttributes {
+    #[serde(default)]
+    categories: Vec<String>,
+    #[serde(default)]
+    scores: Vec<f32>,
+}
+
+// Imagen models served through the `:predict` endpoint
+#[derive(Debug)]
+pub struct ImagenProvider {
+    client: reqwest::Client,
+    api_key: String,
+    base_url: String,
+    models: Vec<ModelInfo>,
+}
+
+impl ImagenProvider {
+    pub const NAME: &'static str = "imagen";
+
+    pub const DEFAULT_MODEL: &'static str = "imagen-3.0-generate-002";
+
+    pub fn new(
+        client: reqwest::Client,
+        api_key: String,
+        base_url: String,
+        model: Option<String>,
+    ) -> Self {
+        Self {
+            client,
+            api_key,
+            base_url,
+            // Unknown model ids are kept so that new model versions work without a release
+            models: model_list(
+                MODELS,
+                model.as_deref().unwrap_or(Self::DEFAULT_MODEL),
+                IMAGEN_CAPABILITIES,
+            ),
+        }
+    }
+
+    #[instrument(skip(self, request), fields(model = %request.model, prompt_length = request.prompt.len()))]
+    async fn predict(&self, request: &GenerationRequest) -> Result<ProviderOutput, ProviderError> {
+        // Create the request
+        let body = ImagenRequest {
+            instances: vec![ImagenInstance {
+                prompt: request.prompt.clone(),
+            }],
+            parameters: ImagenParameters {
+                sample_count: request.sample_count,
+                aspect_ratio: request.aspect_ratio.clone(),
+                negative_prompt: request.negative_prompt.clone(),
+                seed: request.seed,
+                add_watermark: request.add_watermark,
+                enhance_prompt: request.enhance_prompt,
+                guidance_scale: request.guidance_scale,
+                include_rai_reason: true,
+                include_safety_attributes: true,
+            },
+        };
+        info!(
+            "Sending request to Imagen: {}",
+            serde_json::to_string(&body)?
+        );
+        self.send_predict(&request.model, &body, request.sample_count)
+            .await
+    }
+
+    // `sample_count` is the number of images asked for, the ones missing from
+    // the response were filtered
+    async fn send_predict(
+        &self,
+        model: &str,
+        body: &impl Serialize,
+        sample_count: u32,
+    ) -> Result<ProviderOutput, ProviderError> {
+        // Create URL with API key
+        let url = format!(
+            "{}/v1beta/models/{}:predict?key={}",
+            self.base_url, model, self.api_key
+        );
+
+        // Make the request
+        let response_result = self.client.post(&url).json(body).send().await;
+
+        let response = match response_result {
+            Ok(resp) => resp,
+            Err(e) => {
+                let e = e.without_url();
+                error!("Failed to send request to Imagen: {}", e);
+                return Err(e.into());
+            }
+        };
+        let status = response.status();
+        let headers = response.headers().clone();
+        let response_text = response.text().await?;
+        if !status.is_success() {
+            error!(%status, response_body = %response_text, "Imagen returned an error.");
+            return Err(ProviderError::from_response(
+                status,
+                &headers,
+                &response_text,
+            ));
+        }
+
+        let response: ImagenResponse = match serde_json::from_str(&response_text) {
+            Ok(response) => response,
+            Err(e) => {
+                error!(
+                    response_body = %response_text,
+                    "Failed to parse Imagen response: {}",
+                    e
+                );
+                return Err(ProviderError::Decode(format!(
+                    "Failed to parse Imagen response: {}
+The response was: {}",
+                    e, response_text
+                )));
+            }
+        };
+
+        let mut output = ProviderOutput::default();
+        for pred in response.predictions.unwrap_or_default() {
+            let Some(bytes_base64_encoded) = pred.bytes_base64_encoded else {
+                if let Some(reason) = pred.rai_filtered_reason {
+                    output.filtered_reasons.push(reason);
+                }
+                // Entries such as the safety attributes of the prompt carry no image
+                continue;
+            };
+            // Decode the base64 image
+            let bytes =
+                match base64::engine::general_purpose::STANDARD.decode(&bytes_base64_encoded) {
+                    Ok(data) => data,
+                    Err(e) => {
+                        error!("Failed to decode base64 image: {}", e);
+                        return Err(e.into());
+                    }
+                };
+            let safety_attributes = pred
+                .safety_attributes
+                .map(|attributes| {
+                    attributes
+                        .categories
+                        .into_iter()
+                        .zip(attributes.scores)
+                        .map(|(category, score)| SafetyAttribute { category, score })
+                        .collect()
+                })
+                .unwrap_or_default();
+            output.images.push(ProviderImage {
+                bytes,
+                mime_type: pred.mime_type.unwrap_or_else(|| "image/png".to_string()),
+                response: (!pred.other.is_empty()).then_some(serde_json::Value::Object(pred.other)),
+                safety_attributes,
+            });
+        }
+        output.filtered_count = sample_count.saturating_sub(output.images.len() as u32);
+
+        if output.filtered_count > 0 {
+            warn!(
+                filtered_count = output.filtered_count,
+                reasons = ?output.filtered_reasons,
+                "Imagen filtered some of the images."
+            );
+        }
+        output.check_filtered()
+    }
+}
+
+impl ImageProvider for ImagenProvider {
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
+
+    fn models(&self) -> &[ModelInfo] {
+        &self.models
+    }
+
+    fn generate<'a>(&'a self, request: &'a GenerationRequest) -> ProviderFuture<'a> {
+        Box::pin(self.predict(request))
+    }
+}
@@ -0,0 +1,184 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io::Cursor;
+
+use image::{DynamicImage, ImageBuffer, ImageOutputFormat, Rgb, RgbImage};
+use tracing::info;
+
+use super::{
+    COMMON_ASPECT_RATIOS, EditMode, EditRequest, GenerationRequest, ImageProvider,
+    ModelCapabilities, ModelInfo, ProviderError, ProviderFuture, ProviderImage, ProviderOutput,
+};
+
+// Offline provider that renders a gradient locally. Useful for trying out the
+// server without an API key and for exercising clients in CI.
+#[derive(Debug)]
+pub struct MockProvider {
+    models: Vec<ModelInfo>,
+}
+
+impl MockProvider {
+    pub const NAME: &'static str = "mock";
+
+    pub fn new() -> Self {
+        Self {
+            models: vec![ModelInfo {
+                id: "mock".to_string(),
+                alias: None,
+                capabilities: ModelCapabilities {
+                    aspect_ratios: COMMON_ASPECT_RATIOS,
+                    max_sample_count: 4,
+                    negative_prompt: true,
+                    seed: true,
+                    guidance_scale: false,
+                    enhance_prompt: false,
+                    editing: true,
+                },
+            }],
+        }
+    }
+
+    // Small images keep the mock fast, the aspect ratio is still honoured
+    fn dimensions(aspect_ratio: Option<&str>) -> (u32, u32) {
+        match aspect_ratio {
+            Some("3:4") => (384, 512),
+            Some("4:3") => (512, 384),
+            Some("9:16") => (288, 512),
+            Some("16:9") => (512, 288),
+            _ => (512, 512),
+        }
+    }
+
+    // The same prompt and seed always produce the same colours
+    fn colours(key: impl Hash) -> ([u8; 3], [u8; 3]) {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let bytes = hasher.finish().to_le_bytes();
+        (
+            [bytes[0], bytes[1], bytes[2]],
+            [bytes[3], bytes[4], bytes[5]],
+        )
+    }
+
+    fn render(request: &GenerationRequest, sample: u32) -> Result<ProviderImage, ProviderError> {
+        let (width, height) = Self::dimensions(request.aspect_ratio.as_deref());
+        let colours = Self::colours((
+            &request.prompt,
+            &request.negative_prompt,
+            request.seed,
+            sample,
+        ));
+        let buffer = ImageBuffer::from_fn(width, height, |x, y| {
+            Self::gradient(colours, x, y, width, height)
+        });
+        Self::encode(buffer)
+    }
+
+    fn gradient(
+        (from, to): ([u8; 3], [u8; 3]),
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    ) -> Rgb<u8> {
+        let t = (x + y) as f32 / (width + height) as f32;
+        let channel = |i: usize| (from[i] as f32 + (to[i] as f32 - from[i] as f32) * t) as u8;
+        Rgb([channel(0), channel(1), channel(2)])
+    }
+
+    fn encode(buffer: RgbImage) -> Result<ProviderImage, ProviderError> {
+        let mut bytes = Cursor::new(Vec::new());
+        DynamicImage::ImageRgb8(buffer).write_to(&mut bytes, ImageOutputFormat::Png)?;
+        Ok(ProviderImage {
+            bytes: bytes.into_inner(),
+            mime_type: "image/png".to_string(),
+            response: None,
+            safety_attributes: Vec::new(),
+        })
+    }
+
+    // Paint a gradient over the masked area, or the whole image without a
+    // mask. Removal fills the area with the average colour of the rest.
+    fn render_edit(request: &EditRequest, sample: u32) -> Result<ProviderImage, ProviderError> {
+        let mut buffer = image::load_from_memory(&request.image.bytes)?.to_rgb8();
+        let mask = match &request.mask {
+            Some(mask) => Some(image::load_from_memory(&mask.bytes)?.to_luma8()),
+            None => None,
+        };
+        let selected = |x: u32, y: u32| {
+            mask.as_ref()
+                .is_none_or(|mask| mask.get_pixel(x, y).0[0] >= 128)
+        };
+
+        let (mut sum, mut count) = ([0u64; 3], 0u64);
+        for (x, y, pixel) in buffer.enumerate_pixels() {
+            if !selected(x, y) {
+                for (total, channel) in sum.iter_mut().zip(pixel.0) {
+                    *total += channel as u64;
+                }
+                count += 1;
+            }
+        }
+        let average = Rgb(sum.map(|channel| (channel / count.max(1)) as u8));
+
+        let colours = Self::colours((
+            &request.prompt,
+            request.mode,
+            &request.negative_prompt,
+            request.seed,
+            sample,
+        ));
+        let (width, height) = buffer.dimensions();
+        for (x, y, pixel) in buffer.enumerate_pixels_mut() {
+            if !selected(x, y) {
+                continue;
+            }
+            let paint = Self::gradient(colours, x, y, width, height);
+            *pixel = match request.mode {
+                EditMode::InpaintRemove => average,
+                EditMode::Outpaint => paint,
+                EditMode::InpaintInsert | EditMode::BackgroundSwap => {
+                    Rgb([0, 1, 2].map(|i| ((pixel.0[i] as u16 + paint.0[i] as u16) / 2) as u8))
+                }
+            };
+        }
+        Self::encode(buffer)
+    }
+}
+
+impl ImageProvider for MockProvider {
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
+
+    fn models(&self) -> &[ModelInfo] {
+        &self.models
+    }
+
+    fn generate<'a>(&'a self, request: &'a GenerationRequest) -> ProviderFuture<'a> {
+        Box::pin(async move {
+            info!(
+                sample_count = request.sample_count,
+                "Rendering mock images."
+            );
+            (0..request.sample_count)
+                .map(|sample| Self::render(request, sample))
+                .collect::<Result<Vec<_>, _>>()
+                .map(ProviderOutput::from)
+        })
+    }
+
+    fn edit<'a>(&'a self, request: &'a EditRequest) -> ProviderFuture<'a> {
+        Box::pin(async move {
+            info!(
+                sample_count = request.sample_count,
+                mode = request.mode.as_str(),
+                "Rendering mock edits."
+            );
+            (0..request.sample_count)
+                .map(|sample| Self::render_edit(request, sample))
+                .collect::<Result<Vec<_>, _>>()
+                .map(ProviderOutput::from)
+        })
+    }
+}
@@ -0,0 +1,326 @@
+use std::io::Cursor;
+
+use base64::Engine as _;
+use image::{ImageOutputFormat, Rgba, RgbaImage};
+use reqwest::multipart::{Form, Part};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, instrument};
+
+use crate::encoding::OutputFormat;
+
+use super::{
+    COMMON_ASPECT_RATIOS, EditRequest, GenerationRequest, ImageProvider, ModelCapabilities,
+    ModelInfo, ProviderError, ProviderFuture, ProviderImage, ProviderOutput, model_list,
+    with_negative_prompt,
+};
+
+const GPT_IMAGE_CAPABILITIES: ModelCapabilities = ModelCapabilities {
+    aspect_ratios: COMMON_ASPECT_RATIOS,
+    max_sample_count: 4,
+    negative_prompt: true,
+    // The images API has no seed or guidance parameter
+    seed: false,
+    guidance_scale: false,
+    enhance_prompt: false,
+    editing: true,
+};
+
+const MODELS: &[(&str, Option<&str>, ModelCapabilities)] = &[
+    ("gpt-image-1", None, GPT_IMAGE_CAPABILITIES),
+    (
+        "dall-e-3",
+        None,
+        ModelCapabilities {
+            max_sample_count: 1,
+            editing: false,
+            ..GPT_IMAGE_CAPABILITIES
+        },
+    ),
+];
+
+// Request and response structures for the OpenAI images API
+#[derive(Debug, Serialize)]
+struct ImagesRequest<'a> {
+    model: &'a str,
+    prompt: String,
+    n: u32,
+    size: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_format: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output_format: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output_compression: Option<u8>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ImagesResponse {
+    #[serde(default)]
+    data: Vec<ImageData>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ImageData {
+    b64_json: Option<String>,
+    revised_prompt: Option<String>,
+}
+
+// Any service implementing the OpenAI `/v1/images/generations` and
+// `/v1/images/edits` endpoints
+#[derive(Debug)]
+pub struct OpenAiProvider {
+    client: reqwest::Client,
+    api_key: String,
+    base_url: String,
+    models: Vec<ModelInfo>,
+}
+
+impl OpenAiProvider {
+    pub const NAME: &'static str = "openai";
+
+    pub const DEFAULT_MODEL: &'static str = "gpt-image-1";
+
+    pub const DEFAULT_BASE_URL: &'static str = "https://api.openai.com";
+
+    pub fn new(
+        client: reqwest::Client,
+        api_key: String,
+        base_url: Option<String>,
+        model: Option<String>,
+    ) -> Self {
+        Self {
+            client,
+            api_key,
+            base_url: base_url.unwrap_or_else(|| Self::DEFAULT_BASE_URL.to_string()),
+            models: model_list(
+                MODELS,
+                model.as_deref().unwrap_or(Self::DEFAULT_MODEL),
+                GPT_IMAGE_CAPABILITIES,
+            ),
+        }
+    }
+
+    fn is_dall_e(model: &str) -> bool {
+        model.starts_with("dall-e")
+    }
+
+    // gpt-image models write PNG, JPEG and WebP themselves, anything else is
+    // converted after generation
+    fn output_format(request: &GenerationRequest) -> Option<&'static str> {
+        if Self::is_dall_e(&request.model) {
+            return None;
+        }
+        match request.output_format? {
+            OutputFormat::Avif => None,
+            format => Some(format.as_str()),
+        }
+    }
+
+    // The images API takes pixel sizes instead of aspect ratios, so pick the
+    // closest size the model supports.
+    fn size(model: &str, aspect_ratio: Option<&str>) -> &'static str {
+        let orientation = match aspect_ratio {
+            Some("3:4") | Some("9:16") => "portrait",
+            Some("4:3") | Some("16:9") => "landscape",
+            _ => "square",
+        };
+        match (Self::is_dall_e(model), orientation) {
+            (true, "portrait") => "1024x1792",
+            (true, "landscape") => "1792x1024",
+            (false, "portrait") => "1024x1536",
+            (false, "landscape") => "1536x1024",
+            _ => "1024x1024",
+        }
+    }
+
+    // The edits endpoint edits the transparent area of the mask, while our
+    // masks are white where the image may change
+    fn alpha_mask(mask: &ProviderImage) -> Result<Vec<u8>, ProviderError> {
+        let luma = image::load_from_memory(&mask.bytes)?.to_luma8();
+        let alpha = RgbaImage::from_fn(luma.width(), luma.height(), |x, y| {
+            Rgba([0, 0, 0, 255 - luma.get_pixel(x, y).0[0]])
+        });
+        let mut bytes = Cursor::new(Vec::new());
+        alpha.write_to(&mut bytes, ImageOutputFormat::Png)?;
+        Ok(bytes.into_inner())
+    }
+
+    fn url(&self, endpoint: &str) -> String {
+        format!(
+            "{}/v1/images/{}",
+            self.base_url.trim_end_matches('/'),
+            endpoint
+        )
+    }
+
+    #[instrument(skip(self, request), fields(model = %request.model, prompt_length = request.prompt.len()))]
+    async fn generations(
+        &self,
+        request: &GenerationRequest,
+    ) -> Result<ProviderOutput, ProviderError> {
+        let body = ImagesRequest {
+            model: &request.model,
+            // The images API has no negative prompt parameter, so it is folded
+            // into the prompt text instead
+            prompt: with_negative_prompt(
+                request.prompt.clone(),
+                request.negative_prompt.as_deref(),
+            ),
+            n: request.sample_count,
+            size: Self::size(&request.model, request.aspect_ratio.as_deref()),
+            // gpt-image models always return base64 and reject this field
+            response_format: Self::is_dall_e(&request.model).then_some("b64_json"),
+            output_format: Self::output_format(request),
+            output_compression: Self::output_format(request)
+                .filter(|format| *format != "png")
+                .and(request.quality),
+        };
+        info!(
+            "Sending request to OpenAI: {}",
+            serde_json::to_string(&body)?
+        );
+
+        let request_builder = self
+            .client
+            .post(self.url("generations"))
+            .bearer_auth(&self.api_key)
+            .json(&body);
+        self.send(request_builder).await
+    }
+
+    #[instrument(skip(self, request), fields(model = %request.model, mode = request.mode.as_str()))]
+    async fn edits(&self, request: &EditRequest) -> Result<ProviderOutput, ProviderError> {
+        let prompt = with_negative_prompt(
+            request
+                .mode
+                .instruction(&request.prompt, request.mask.is_some()),
+            request.negative_prompt.as_deref(),
+        );
+        info!(%prompt, "Sending edit request to OpenAI.");
+
+        let file_name = match request.image.mime_type.as_str() {
+            "image/jpeg" => "image.jpg",
+            "image/webp" => "image.webp",
+            _ => "image.png",
+        };
+        let image = Part::bytes(request.image.bytes.clone())
+            .file_name(file_name)
+            .mime_str(&request.image.mime_type)?;
+        let mut form = Form::new()
+            .text("model", request.model.clone())
+            .text("prompt", prompt)
+            .text("n", request.sample_count.to_string())
+            .part("image", image);
+        if let Some(mask) = &request.mask {
+            let mask = Part::bytes(Self::alpha_mask(mask)?)
+                .file_name("mask.png")
+                .mime_str("image/png")?;
+            form = form.part("mask", mask);
+        }
+
+        let request_builder = self
+            .client
+            .post(self.url("edits"))
+            .bearer_auth(&self.api_key)
+            .multipart(form);
+        self.send(request_builder).await
+    }
+
+    async fn send(
+        &self,
+        request_builder: reqwest::RequestBuilder,
+    ) -> Result<ProviderOutput, ProviderError> {
+        let response = match request_builder.send().await {
+            Ok(resp) => resp,
+            Err(e) => {
+                error!("Failed to send request to OpenAI: {}", e);
+                return Err(e.into());
+            }
+        };
+        let status = response.status();
+        let headers = response.headers().clone();
+        let response_text = response.text().await?;
+        if !status.is_success() {
+            error!(%status, response_body = %response_text, "OpenAI returned an error.");
+            return Err(ProviderError::from_response(
+                status,
+                &headers,
+                &response_text,
+            ));
+        }
+
+        let response: ImagesResponse = match serde_json::from_str(&response_text) {
+            Ok(response) => response,
+            Err(e) => {
+                error!(
+                    response_body = %response_text,
+                    "Failed to parse OpenAI response: {}",
+                    e
+                );
+                return Err(ProviderError::Decode(format!(
+                    "Failed to parse OpenAI response: {}
+The response was: {}",
+                    e, response_text
+                )));
+            }
+        };
+
+        let mut images = Vec::new();
+        for data in response.data {
+            let Some(b64_json) = data.b64_json else {
+                continue;
+            };
+            let bytes = match base64::engine::general_purpose::STANDARD.decode(&b64_json) {
+                Ok(data) => data,
+                Err(e) => {
+                    error!("Failed to decode base64 image: {}", e);
+                    return Err(e.into());
+                }
+            };
+            // The format depends on the request, so take it from the data
+            let mime_type = image::guess_format(&bytes)
+                .map(|format| format.to_mime_type())
+                .unwrap_or("image/png");
+            images.push(ProviderImage {
+                mime_type: mime_type.to_string(),
+                bytes,
+                response: data
+                    .revised_prompt
+                    .map(|revised_prompt| serde_json::json!({ "revised_prompt": revised_prompt })),
+                safety_attributes: Vec::new(),
+            });
+        }
+
+        if images.is_empty() {
+            error!("No images were generated by OpenAI.");
+            return Err(ProviderError::Decode(format!(
+                "No images were generated. The response was: {}",
+                response_text
+            )));
+        }
+
+        Ok(images.into())
+    }
+}
+
+impl ImageProvider for OpenAiProvider {
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
+
+    fn models(&self) -> &[ModelInfo] {
+        &self.models
+    }
+
+    fn generate<'a>(&'a self, request: &'a GenerationRequest) -> ProviderFuture<'a> {
+        Box::pin(self.generations(request))
+    }
+
+    fn encodes_output(&self, request: &GenerationRequest) -> bool {
+        Self::output_format(request).is_some()
+    }
+
+    fn edit<'a>(&'a self, request: &'a EditRequest) -> ProviderFuture<'a> {
+        Box::pin(self.edits(request))
+    }
+}
@@ -0,0 +1,296 @@
+use std::env;
+use std::future::Future;
+use std::time::Duration;
+
+use rand::Rng;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use super::ProviderError;
+
+const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+const DEFAULT_BASE_DELAY_MS: u64 = 1000;
+const DEFAULT_MAX_DELAY_MS: u64 = 30_000;
+// Rate limits and the errors of busy or restarting servers
+const DEFAULT_STATUSES: &[u16] = &[429, 500, 502, 503, 504];
+
+// When and how often a failed provider call is repeated. The delay doubles
+// with every attempt, up to `max_delay`, and a random part of it is left out
+// so that clients that failed together do not retry together.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    // Calls in total, 1 turns retries off
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    // HTTP statuses worth another attempt, 429 also covers quota errors
+    pub statuses: Vec<u16>,
+    // Whether connection errors and timeouts are retried
+    pub network_errors: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            base_delay: Duration::from_millis(DEFAULT_BASE_DELAY_MS),
+            max_delay: Duration::from_millis(DEFAULT_MAX_DELAY_MS),
+            statuses: DEFAULT_STATUSES.to_vec(),
+            network_errors: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    // Read the policy from RETRY_MAX_ATTEMPTS, RETRY_BASE_DELAY_MS,
+    // RETRY_MAX_DELAY_MS, RETRY_STATUSES and RETRY_NETWORK_ERRORS
+    pub fn from_env() -> Result<Self, String> {
+        fn var<T>(name: &str, parse: impl FnOnce(&str) -> Option<T>) -> Result<Option<T>, String> {
+            match env::var(name) {
+                Ok(value) if !value.trim().is_empty() => parse(value.trim())
+                    .map(Some)
+                    .ok_or_else(|| format!("Invalid {}: {}", name, value)),
+                _ => Ok(None),
+            }
+        }
+
+        let defaults = Self::default();
+        let millis = |value: &str| value.parse().ok().map(Duration::from_millis);
+        Ok(Self {
+            max_attempts: var("RETRY_MAX_ATTEMPTS", |value| {
+                value.parse().ok().filter(|attempts| *attempts > 0)
+            })?
+            .unwrap_or(defaults.max_attempts),
+            base_delay: var("RETRY_BASE_DELAY_MS", millis)?.unwrap_or(defaults.base_delay),
+            max_delay: var("RETRY_MAX_DELAY_MS", millis)?.unwrap_or(defaults.max_delay),
+            // A comma separated list, e.g. "429,503"
+            statuses: var("RETRY_STATUSES", |value| {
+                value
+                    .split(',')
+                    .map(|status| status.trim())
+                    .filter(|status| !status.is_empty())
+                    .map(|status| status.parse().ok())
+                    .collect()
+            })?
+            .unwrap_or(defaults.statuses),
+            network_errors: var("RETRY_NETWORK_ERRORS", |value| {
+                match value.to_ascii_lowercase().as_str() {
+                    "true" | "1" | "yes" => Some(true),
+                    "false" | "0" | "no" => Some(false),
+                    _ => None,
+                }
+            })?
+            .unwrap_or(defaults.network_errors),
+        })
+    }
+
+    // Whether the policy retries this error at all
+    pub fn is_retryable(&self, error: &ProviderError) -> bool {
+        match error {
+            ProviderError::Http { status, .. } => self.statuses.contains(status),
+            ProviderError::QuotaExceeded { .. } => self.statuses.contains(&429),
+            ProviderError::Network(_) | ProviderError::Timeout(_) => self.network_errors,
+            _ => false,
+        }
+    }
+
+    // How long to wait after the given failed attempt, counted from 1. The
+    // API's own Retry-After wins, unless it is longer than `max_delay`, in
+    // which case waiting is left to the client.
+    fn delay(&self, attempt: u32, error: &ProviderError) -> Option<Duration> {
+        if let Some(retry_after) = error.retry_after() {
+            return (retry_after <= self.max_delay).then_some(retry_after);
+        }
+        let backoff = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt - 1))
+            .min(self.max_delay);
+        // Keep half of the delay and pick the rest at random
+        let half = backoff / 2;
+        Some(half + half.mul_f64(rand::thread_rng().r#gen::<f64>()))
+    }
+
+    // Call `call` until it succeeds, fails with an error the policy does not
+    // retry, or runs out of attempts. Cancelling `ct` drops the request in
+    // flight, or stops waiting for the next one. Returns the result together
+    // with the number of calls made.
+    pub async fn run<T, F, Fut>(
+        &self,
+        ct: &CancellationToken,
+        mut call: F,
+    ) -> (Result<T, ProviderError>, u32)
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, ProviderError>>,
+    {
+        let mut attempt = 1;
+        loop {
+            let result = tokio::select! {
+                result = call() => result,
+                _ = ct.cancelled() => Err(ProviderError::Cancelled),
+            };
+            let error = match result {
+                Ok(value) => return (Ok(value), attempt),
+                Err(ProviderError::Cancelled) => {
+                    info!(attempt, "Provider call cancelled.");
+                    return (Err(ProviderError::Cancelled), attempt);
+                }
+                Err(error) => error,
+            };
+            let delay = (attempt < self.max_attempts && self.is_retryable(&error))
+                .then(|| self.delay(attempt, &error))
+                .flatten();
+            let Some(delay) = delay else {
+                return (Err(error), attempt);
+            };
+            warn!(
+                attempt,
+                max_attempts = self.max_attempts,
+                delay_ms = delay.as_millis() as u64,
+                code = error.code(),
+                "Provider call failed, retrying: {}",
+                error
+            );
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = ct.cancelled() => return (Err(ProviderError::Cancelled), attempt),
+            }
+            attempt += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(4),
+            ..Default::default()
+        }
+    }
+
+    fn unavailable() -> ProviderError {
+        ProviderError::Http {
+            status: 503,
+            message: "Service Unavailable".to_string(),
+        }
+    }
+
+    fn quota(retry_after: Duration) -> ProviderError {
+        ProviderError::QuotaExceeded {
+            message: "Too Many Requests".to_string(),
+            retry_after: Some(retry_after),
+        }
+    }
+
+    #[test]
+    fn delay_doubles_up_to_max_delay() {
+        let policy = RetryPolicy {
+            base_delay: Duration::from_millis(1000),
+            max_delay: Duration::from_millis(5000),
+            ..Default::default()
+        };
+        for (attempt, full) in [(1, 1000), (2, 2000), (3, 4000), (4, 5000), (10, 5000)] {
+            let full = Duration::from_millis(full);
+            for _ in 0..50 {
+                let delay = policy.delay(attempt, &unavailable()).unwrap();
+                assert!(delay >= full / 2, "attempt {}: {:?}", attempt, delay);
+                assert!(delay <= full, "attempt {}: {:?}", attempt, delay);
+            }
+        }
+    }
+
+    #[test]
+    fn delay_survives_huge_attempt_numbers() {
+        let policy = RetryPolicy::default();
+        let delay = policy.delay(u32::MAX, &unavailable()).unwrap();
+        assert!(delay <= policy.max_delay);
+    }
+
+    #[test]
+    fn delay_honors_retry_after() {
+        let policy = RetryPolicy::default();
+        let retry_after = Duration::from_secs(7);
+        assert_eq!(policy.delay(1, &quota(retry_after)), Some(retry_after));
+        // Longer than max_delay, the client decides whether to wait
+        assert_eq!(policy.delay(1, &quota(Duration::from_secs(60))), None);
+    }
+
+    #[test]
+    fn retries_only_configured_errors() {
+        let policy = RetryPolicy {
+            statuses: vec![503],
+            network_errors: false,
+            ..Default::default()
+        };
+        assert!(policy.is_retryable(&unavailable()));
+        assert!(!policy.is_retryable(&quota(Duration::from_secs(1))));
+        assert!(!policy.is_retryable(&ProviderError::Network("reset".to_string())));
+        assert!(!policy.is_retryable(&ProviderError::InvalidParameter("bad".to_string())));
+        assert!(!policy.is_retryable(&ProviderError::SafetyFiltered("blocked".to_string())));
+    }
+
+    #[tokio::test]
+    async fn run_stops_at_non_retryable_errors() {
+        let mut calls = 0;
+        let (result, attempts) = policy()
+            .run(&CancellationToken::new(), || {
+                calls += 1;
+                async { Err::<(), _>(ProviderError::PermissionDenied("no".to_string())) }
+            })
+            .await;
+        assert!(matches!(result, Err(ProviderError::PermissionDenied(_))));
+        assert_eq!(attempts, 1);
+        assert_eq!(calls, 1);
+    }
+
+    #[tokio::test]
+    async fn run_retries_until_success() {
+        let mut calls = 0;
+        let (result, attempts) = policy()
+            .run(&CancellationToken::new(), || {
+                calls += 1;
+                let result = if calls < 3 {
+                    Err(unavailable())
+                } else {
+                    Ok(calls)
+                };
+                async move { result }
+            })
+            .await;
+        assert_eq!(result.unwrap(), 3);
+        assert_eq!(attempts, 3);
+    }
+
+    #[tokio::test]
+    async fn run_gives_up_after_max_attempts() {
+        let mut calls = 0;
+        let (result, attempts) = policy()
+            .run(&CancellationToken::new(), || {
+                calls += 1;
+                async { Err::<(), _>(unavailable()) }
+            })
+            .await;
+        assert!(matches!(
+            result,
+            Err(ProviderError::Http { status: 503, .. })
+        ));
+        assert_eq!(attempts, 3);
+        assert_eq!(calls, 3);
+    }
+
+    #[tokio::test]
+    async fn run_stops_when_cancelled() {
+        let ct = CancellationToken::new();
+        ct.cancel();
+        let (result, attempts) = policy()
+            .run(&ct, std::future::pending::<Result<(), ProviderError>>)
+            .await;
+        assert!(matches!(result, Err(ProviderError::Cancelled)));
+        assert_eq!(attempts, 1);
+    }
+}
@@ -0,0 +1,225 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use base64::Engine as _;
+use rmcp::model::{
+    AnnotateAble, RawResource, RawResourceTemplate, Resource, ResourceContents, ResourceTemplate,
+    ResourceUpdatedNotificationParam,
+};
+use rmcp::{Peer, RoleServer};
+use tracing::error;
+
+// The gallery itself, reading it returns a JSON index of all images
+pub const GALLERY_URI: &str = "imagen://images";
+
+const IMAGE_URI_PREFIX: &str = "imagen://images/";
+
+pub fn image_uri(filename: &str) -> String {
+    format!("{}{}", IMAGE_URI_PREFIX, filename)
+}
+
+// Extract the filename from an image URI. Anything that could escape the
+// images directory is rejected.
+pub fn filename_from_uri(uri: &str) -> Option<&str> {
+    let filename = uri.strip_prefix(IMAGE_URI_PREFIX)?;
+    is_safe_filename(filename).then_some(filename)
+}
+
+pub fn is_safe_filename(filename: &str) -> bool {
+    !filename.is_empty()
+        && filename != "."
+        && filename != ".."
+        && !filename.contains(['/', '\\', '\0'])
+}
+
+// Whether a new filename can be used in URLs as is. Names chosen by clients
+// are limited to these characters, image URLs are not encoded.
+pub fn is_url_safe_filename(filename: &str) -> bool {
+    is_safe_filename(filename)
+        && filename
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+}
+
+pub fn mime_type_for(filename: &str) -> &'static str {
+    let extension = Path::new(filename)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("webp") => "image/webp",
+        Some("avif") => "image/avif",
+        Some("gif") => "image/gif",
+        _ => "application/octet-stream",
+    }
+}
+
+// The file extension for images of a MIME type
+pub fn extension_for(mime_type: &str) -> &'static str {
+    match mime_type {
+        "image/jpeg" => "jpg",
+        "image/webp" => "webp",
+        "image/avif" => "avif",
+        "image/gif" => "gif",
+        _ => "png",
+    }
+}
+
+// An image file in the gallery
+#[derive(Debug)]
+pub struct GalleryEntry {
+    pub filename: String,
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
+// All images in the images directory, newest first
+pub async fn gallery_entries(images_dir: &Path) -> std::io::Result<Vec<GalleryEntry>> {
+    let mut entries = Vec::new();
+    let mut dir = tokio::fs::read_dir(images_dir).await?;
+    while let Some(entry) = dir.next_entry().await? {
+        let metadata = match entry.metadata().await {
+            Ok(metadata) => metadata,
+            // Deleted or renamed since the directory was read
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        if !metadata.is_file() {
+            continue;
+        }
+        if let Some(filename) = entry.file_name().to_str() {
+            entries.push(GalleryEntry {
+                filename: filename.to_string(),
+                size: metadata.len(),
+                modified: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+            });
+        }
+    }
+    entries.sort_by_key(|entry| std::cmp::Reverse(entry.modified));
+    Ok(entries)
+}
+
+pub fn gallery_resource() -> Resource {
+    RawResource {
+        uri: GALLERY_URI.to_string(),
+        name: "Image gallery".to_string(),
+        description: Some(
+            "JSON index of all generated images. Subscribe to be notified when images are added."
+                .to_string(),
+        ),
+        mime_type: Some("application/json".to_string()),
+        size: None,
+    }
+    .no_annotation()
+}
+
+pub fn image_resource(entry: &GalleryEntry) -> Resource {
+    RawResource {
+        uri: image_uri(&entry.filename),
+        name: entry.filename.clone(),
+        description: None,
+        mime_type: Some(mime_type_for(&entry.filename).to_string()),
+        size: u32::try_from(entry.size).ok(),
+    }
+    .no_annotation()
+}
+
+pub fn image_resource_template() -> ResourceTemplate {
+    RawResourceTemplate {
+        uri_template: format!("{}{{filename}}", IMAGE_URI_PREFIX),
+        name: "Generated image".to_string(),
+        description: Some("An image from the gallery, by filename.".to_string()),
+        mime_type: None,
+    }
+    .no_annotation()
+}
+
+pub fn gallery_contents(entries: &[GalleryEntry]) -> ResourceContents {
+    let index = entries
+        .iter()
+        .map(|entry| {
+            serde_json::json!({
+                "uri": image_uri(&entry.filename),
+                "filename": entry.filename,
+                "mime_type": mime_type_for(&entry.filename),
+                "size": entry.size,
+            })
+        })
+        .collect::<Vec<_>>();
+    ResourceContents::TextResourceContents {
+        uri: GALLERY_URI.to_string(),
+        mime_type: Some("application/json".to_string()),
+        text: serde_json::Value::Array(index).to_string(),
+    }
+}
+
+pub fn image_contents(filename: &str, bytes: &[u8]) -> ResourceContents {
+    ResourceContents::BlobResourceContents {
+        uri: image_uri(filename),
+        mime_type: Some(mime_type_for(filename).to_string()),
+        blob: base64::engine::general_purpose::STANDARD.encode(bytes),
+    }
+}
+
+// A connected session and the resource URIs its client subscribed to
+#[derive(Debug, Clone)]
+struct Subscriber {
+    peer: Peer<RoleServer>,
+    uris: Arc<Mutex<HashSet<String>>>,
+}
+
+// All connected sessions, by session id. Shared by the sessions so that
+// changes made in one of them reach the clients of all of them.
+#[derive(Debug, Clone, Default)]
+pub struct Subscribers(Arc<Mutex<HashMap<String, Subscriber>>>);
+
+impl Subscribers {
+    pub fn add(&self, session_id: &str, peer: Peer<RoleServer>, uris: Arc<Mutex<HashSet<String>>>) {
+        self.0
+            .lock()
+            .unwrap()
+            .insert(session_id.to_string(), Subscriber { peer, uris });
+    }
+
+    pub fn remove(&self, session_id: &str) {
+        self.0.lock().unwrap().remove(session_id);
+    }
+
+    // Tell every client that images were added, removed or renamed, and
+    // which of its subscribed resources changed
+    pub async fn notify_gallery_changed(&self, filenames: &[&str]) {
+        let subscribers = self
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(session_id, subscriber)| (session_id.clone(), subscriber.clone()))
+            .collect::<Vec<_>>();
+        for (session_id, subscriber) in subscribers {
+            if let Err(e) = subscriber.peer.notify_resource_list_changed().await {
+                error!(%session_id, "Failed to send resource list changed notification: {}", e);
+            }
+
+            let updated_uris = {
+                let uris = subscriber.uris.lock().unwrap();
+                std::iter::once(GALLERY_URI.to_string())
+                    .chain(filenames.iter().map(|filename| image_uri(filename)))
+                    .filter(|uri| uris.contains(uri))
+                    .collect::<Vec<_>>()
+            };
+            for uri in updated_uris {
+                if let Err(e) = subscriber
+                    .peer
+                    .notify_resource_updated(ResourceUpdatedNotificationParam { uri: uri.clone() })
+                    .await
+                {
+                    error!(%session_id, %uri, "Failed to send resource updated notification: {}", e);
+                }
+            }
+        }
+    }
+}
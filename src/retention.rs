@@ -0,0 +1,453 @@
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::path::Path;
+use std::time::Duration;
+
+use chrono::{DateTime, Local};
+use tracing::{info, warn};
+
+use crate::images;
+use crate::metadata::{ImageMetadata, MetadataStore};
+use crate::resources::{self, Subscribers};
+
+const DEFAULT_INTERVAL_MINUTES: u64 = 60;
+
+// Which images the garbage collection removes. Without any limit nothing is
+// removed and the background task does not run.
+#[derive(Debug, Clone)]
+pub struct RetentionPolicy {
+    pub max_age: Option<Duration>,
+    pub max_total_bytes: Option<u64>,
+    pub max_images: Option<usize>,
+    // Never remove pinned images, even when they break a limit
+    pub keep_pinned: bool,
+    // How often the background task runs
+    pub interval: Duration,
+}
+
+impl RetentionPolicy {
+    // Read the policy from RETENTION_MAX_AGE_DAYS, RETENTION_MAX_TOTAL_BYTES,
+    // RETENTION_MAX_IMAGES, RETENTION_KEEP_PINNED and RETENTION_INTERVAL_MINUTES
+    pub fn from_env() -> Result<Self, String> {
+        fn var<T: std::str::FromStr>(
+            name: &str,
+            parse: impl FnOnce(&str) -> Option<T>,
+        ) -> Result<Option<T>, String> {
+            match env::var(name) {
+                Ok(value) if !value.trim().is_empty() => parse(value.trim())
+                    .map(Some)
+                    .ok_or_else(|| format!("Invalid {}: {}", name, value)),
+                _ => Ok(None),
+            }
+        }
+
+        let max_age_days = var("RETENTION_MAX_AGE_DAYS", |value| value.parse::<u64>().ok())?;
+        let interval_minutes = var("RETENTION_INTERVAL_MINUTES", |value| {
+            value.parse::<u64>().ok().filter(|minutes| *minutes > 0)
+        })?;
+        Ok(Self {
+            max_age: max_age_days.map(|days| Duration::from_secs(days * 24 * 60 * 60)),
+            max_total_bytes: var("RETENTION_MAX_TOTAL_BYTES", parse_size)?,
+            max_images: var("RETENTION_MAX_IMAGES", |value| value.parse().ok())?,
+            keep_pinned: var("RETENTION_KEEP_PINNED", |value| {
+                match value.to_ascii_lowercase().as_str() {
+                    "true" | "1" | "yes" => Some(true),
+                    "false" | "0" | "no" => Some(false),
+                    _ => None,
+                }
+            })?
+            .unwrap_or(true),
+            interval: Duration::from_secs(
+                interval_minutes.unwrap_or(DEFAULT_INTERVAL_MINUTES) * 60,
+            ),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max_age.is_some() || self.max_total_bytes.is_some() || self.max_images.is_some()
+    }
+}
+
+// A size in bytes, optionally with a KB, MB or GB suffix (powers of 1024)
+fn parse_size(value: &str) -> Option<u64> {
+    let upper = value.to_ascii_uppercase();
+    let (number, unit) = match upper.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => upper.split_at(index),
+        None => (upper.as_str(), ""),
+    };
+    let multiplier: u64 = match unit.trim() {
+        "" | "B" => 1,
+        "K" | "KB" => 1 << 10,
+        "M" | "MB" => 1 << 20,
+        "G" | "GB" => 1 << 30,
+        _ => return None,
+    };
+    number.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+// An image removed, or with a dry run to be removed, by the collection
+#[derive(Debug)]
+pub struct RemovedImage {
+    pub filename: String,
+    pub bytes: u64,
+    pub created_at: String,
+    // The limit the image broke: "max_age", "max_images" or "max_total_bytes"
+    pub reason: &'static str,
+}
+
+#[derive(Debug, Default)]
+pub struct GcReport {
+    pub removed: Vec<RemovedImage>,
+    pub freed_bytes: u64,
+    // What is left in the gallery afterwards
+    pub kept_images: usize,
+    pub kept_bytes: u64,
+    pub pinned_images: usize,
+}
+
+struct Candidate {
+    filename: String,
+    bytes: u64,
+    created_at: DateTime<Local>,
+    pinned: bool,
+}
+
+// Decide which images break the policy, oldest first. The age limit applies
+// to every image, the count and size limits remove the oldest images until
+// the rest fits.
+fn plan(
+    mut candidates: Vec<Candidate>,
+    policy: &RetentionPolicy,
+    now: DateTime<Local>,
+) -> GcReport {
+    candidates.sort_by_key(|candidate| candidate.created_at);
+    let protected = |candidate: &Candidate| policy.keep_pinned && candidate.pinned;
+    let mut report = GcReport::default();
+    let mut removed = vec![None; candidates.len()];
+
+    if let Some(max_age) = policy.max_age
+        && let Ok(max_age) = chrono::Duration::from_std(max_age)
+    {
+        for (index, candidate) in candidates.iter().enumerate() {
+            if !protected(candidate) && now - candidate.created_at > max_age {
+                removed[index] = Some("max_age");
+            }
+        }
+    }
+
+    let mut count = removed.iter().filter(|reason| reason.is_none()).count();
+    let mut total: u64 = candidates
+        .iter()
+        .zip(&removed)
+        .filter(|(_, reason)| reason.is_none())
+        .map(|(candidate, _)| candidate.bytes)
+        .sum();
+    for (index, candidate) in candidates.iter().enumerate() {
+        if protected(candidate) || removed[index].is_some() {
+            continue;
+        }
+        let reason = if policy.max_images.is_some_and(|max| count > max) {
+            "max_images"
+        } else if policy.max_total_bytes.is_some_and(|max| total > max) {
+            "max_total_bytes"
+        } else {
+            continue;
+        };
+        removed[index] = Some(reason);
+        count -= 1;
+        total -= candidate.bytes;
+    }
+
+    for (candidate, reason) in candidates.into_iter().zip(removed) {
+        match reason {
+            Some(reason) => {
+                report.freed_bytes += candidate.bytes;
+                report.removed.push(RemovedImage {
+                    filename: candidate.filename,
+                    bytes: candidate.bytes,
+                    created_at: candidate.created_at.to_rfc3339(),
+                    reason,
+                });
+            }
+            None => {
+                report.kept_images += 1;
+                report.kept_bytes += candidate.bytes;
+                if candidate.pinned {
+                    report.pinned_images += 1;
+                }
+            }
+        }
+    }
+    report
+}
+
+// Apply the policy to the images directory. With `dry_run` nothing is
+// deleted, the report lists what would be.
+pub async fn collect(
+    resources_path: &Path,
+    metadata: &MetadataStore,
+    policy: &RetentionPolicy,
+    dry_run: bool,
+) -> Result<GcReport, String> {
+    let images_dir = resources_path.join("images");
+    let entries = resources::gallery_entries(&images_dir)
+        .await
+        .map_err(|e| format!("Failed to list images: {}", e))?;
+    let indexed: HashMap<String, ImageMetadata> = metadata
+        .all()
+        .await
+        .map_err(|e| format!("Failed to read metadata index: {}", e))?
+        .into_iter()
+        .map(|entry| (entry.filename.clone(), entry))
+        .collect();
+
+    // Images are as old as their metadata says, or as their file without it
+    let candidates = entries
+        .into_iter()
+        .map(|entry| {
+            let indexed = indexed.get(&entry.filename);
+            let created_at = indexed
+                .and_then(|indexed| DateTime::parse_from_rfc3339(&indexed.created_at).ok())
+                .map(|created_at| created_at.with_timezone(&Local))
+                .unwrap_or_else(|| entry.modified.into());
+            Candidate {
+                pinned: indexed.is_some_and(|indexed| indexed.pinned),
+                filename: entry.filename,
+                bytes: entry.size,
+                created_at,
+            }
+        })
+        .collect();
+
+    let mut report = plan(candidates, policy, Local::now());
+    if dry_run || report.removed.is_empty() {
+        return Ok(report);
+    }
+
+    let mut deleted = HashSet::new();
+    for image in &report.removed {
+        if let Err(e) = images::remove_file(resources_path, &image.filename).await {
+            warn!(filename = %image.filename, "Failed to remove image: {}", e);
+            continue;
+        }
+        deleted.insert(image.filename.clone());
+    }
+    if let Err(e) = metadata.remove(&deleted).await {
+        warn!(
+            "Failed to remove deleted images from the metadata index: {}",
+            e
+        );
+    }
+
+    // Images that could not be deleted are still in the gallery
+    let (removed, kept): (Vec<_>, Vec<_>) = std::mem::take(&mut report.removed)
+        .into_iter()
+        .partition(|image| deleted.contains(&image.filename));
+    for image in kept {
+        report.freed_bytes -= image.bytes;
+        report.kept_images += 1;
+        report.kept_bytes += image.bytes;
+    }
+    report.removed = removed;
+    Ok(report)
+}
+
+// Run the collection every `policy.interval` until the process exits, and
+// tell the connected clients when images were removed
+pub async fn run_periodically(
+    resources_path: std::path::PathBuf,
+    metadata: MetadataStore,
+    policy: RetentionPolicy,
+    subscribers: Subscribers,
+) {
+    let mut interval = tokio::time::interval(policy.interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        match collect(&resources_path, &metadata, &policy, false).await {
+            Ok(report) if report.removed.is_empty() => {}
+            Ok(report) => {
+                info!(
+                    removed = report.removed.len(),
+                    freed_bytes = report.freed_bytes,
+                    kept_images = report.kept_images,
+                    kept_bytes = report.kept_bytes,
+                    "Removed images by the retention policy."
+                );
+                let filenames = report
+                    .removed
+                    .iter()
+                    .map(|image| image.filename.as_str())
+                    .collect::<Vec<_>>();
+                subscribers.notify_gallery_changed(&filenames).await;
+            }
+            Err(e) => warn!("Garbage collection failed: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    fn policy() -> RetentionPolicy {
+        RetentionPolicy {
+            max_age: None,
+            max_total_bytes: None,
+            max_images: None,
+            keep_pinned: true,
+            interval: Duration::from_secs(60),
+        }
+    }
+
+    // An image created `days_old` days before `now`
+    fn candidate(filename: &str, bytes: u64, days_old: i64, pinned: bool) -> Candidate {
+        Candidate {
+            filename: filename.to_string(),
+            bytes,
+            created_at: now() - chrono::Duration::days(days_old),
+            pinned,
+        }
+    }
+
+    fn now() -> DateTime<Local> {
+        DateTime::parse_from_rfc3339("2025-06-01T12:00:00+00:00")
+            .unwrap()
+            .with_timezone(&Local)
+    }
+
+    fn removed(report: &GcReport) -> Vec<(&str, &str)> {
+        report
+            .removed
+            .iter()
+            .map(|image| (image.filename.as_str(), image.reason))
+            .collect()
+    }
+
+    #[test]
+    fn parse_size_accepts_suffixes() {
+        assert_eq!(parse_size("512"), Some(512));
+        assert_eq!(parse_size("512B"), Some(512));
+        assert_eq!(parse_size("2K"), Some(2 * 1024));
+        assert_eq!(parse_size("2kb"), Some(2 * 1024));
+        assert_eq!(parse_size("500MB"), Some(500 * 1024 * 1024));
+        assert_eq!(parse_size("2 GB"), Some(2 * 1024 * 1024 * 1024));
+    }
+
+    #[test]
+    fn parse_size_rejects_invalid_input() {
+        assert_eq!(parse_size(""), None);
+        assert_eq!(parse_size("GB"), None);
+        assert_eq!(parse_size("2TB"), None);
+        assert_eq!(parse_size("1.5GB"), None);
+        assert_eq!(parse_size("-1"), None);
+        assert_eq!(parse_size("99999999999999999999"), None);
+        assert_eq!(parse_size("17179869184GB"), None);
+    }
+
+    #[test]
+    fn plan_without_limits_keeps_everything() {
+        let candidates = vec![
+            candidate("a.png", 100, 400, false),
+            candidate("b.png", 100, 1, false),
+        ];
+        let report = plan(candidates, &policy(), now());
+        assert!(report.removed.is_empty());
+        assert_eq!(report.kept_images, 2);
+        assert_eq!(report.kept_bytes, 200);
+    }
+
+    #[test]
+    fn plan_removes_images_older_than_max_age() {
+        let policy = RetentionPolicy {
+            max_age: Some(30 * DAY),
+            ..policy()
+        };
+        let candidates = vec![
+            candidate("new.png", 100, 1, false),
+            candidate("old.png", 300, 40, false),
+            candidate("older.png", 200, 90, false),
+        ];
+        let report = plan(candidates, &policy, now());
+        assert_eq!(
+            removed(&report),
+            [("older.png", "max_age"), ("old.png", "max_age")]
+        );
+        assert_eq!(report.freed_bytes, 500);
+        assert_eq!(report.kept_images, 1);
+        assert_eq!(report.kept_bytes, 100);
+    }
+
+    #[test]
+    fn plan_removes_oldest_images_over_max_images() {
+        let policy = RetentionPolicy {
+            max_images: Some(2),
+            ..policy()
+        };
+        let candidates = vec![
+            candidate("c.png", 100, 1, false),
+            candidate("a.png", 100, 3, false),
+            candidate("b.png", 100, 2, false),
+        ];
+        let report = plan(candidates, &policy, now());
+        assert_eq!(removed(&report), [("a.png", "max_images")]);
+        assert_eq!(report.kept_images, 2);
+    }
+
+    #[test]
+    fn plan_removes_oldest_images_over_max_total_bytes() {
+        let policy = RetentionPolicy {
+            max_total_bytes: Some(250),
+            ..policy()
+        };
+        let candidates = vec![
+            candidate("a.png", 100, 3, false),
+            candidate("b.png", 100, 2, false),
+            candidate("c.png", 100, 1, false),
+        ];
+        let report = plan(candidates, &policy, now());
+        assert_eq!(removed(&report), [("a.png", "max_total_bytes")]);
+        assert_eq!(report.freed_bytes, 100);
+        assert_eq!(report.kept_bytes, 200);
+    }
+
+    #[test]
+    fn plan_keeps_pinned_images() {
+        let policy = RetentionPolicy {
+            max_age: Some(30 * DAY),
+            max_images: Some(1),
+            ..policy()
+        };
+        let candidates = vec![
+            candidate("pinned.png", 100, 90, true),
+            candidate("old.png", 100, 90, false),
+            candidate("a.png", 100, 2, false),
+            candidate("b.png", 100, 1, false),
+        ];
+        let report = plan(candidates, &policy, now());
+        let removed = removed(&report);
+        assert!(removed.contains(&("old.png", "max_age")));
+        assert!(removed.contains(&("a.png", "max_images")));
+        assert!(removed.contains(&("b.png", "max_images")));
+        assert_eq!(report.kept_images, 1);
+        assert_eq!(report.pinned_images, 1);
+    }
+
+    #[test]
+    fn plan_removes_pinned_images_without_keep_pinned() {
+        let policy = RetentionPolicy {
+            max_age: Some(30 * DAY),
+            keep_pinned: false,
+            ..policy()
+        };
+        let candidates = vec![
+            candidate("pinned_old.png", 100, 90, true),
+            candidate("pinned_new.png", 100, 1, true),
+        ];
+        let report = plan(candidates, &policy, now());
+        assert_eq!(removed(&report), [("pinned_old.png", "max_age")]);
+        assert_eq!(report.pinned_images, 1);
+    }
+}
@@ -0,0 +1,668 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use base64::Engine as _;
+use chrono::{DateTime, FixedOffset, Local, NaiveDate};
+use rmcp::schemars;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::metadata::{ImageMetadata, MetadataStore};
+use crate::resources;
+use crate::thumbnails;
+
+const DEFAULT_LIMIT: usize = 20;
+const MAX_LIMIT: usize = 100;
+
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, schemars::JsonSchema,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchSort {
+    #[default]
+    Newest,
+    Oldest,
+    // Most matches of the search terms first, only used together with `q`
+    Relevance,
+}
+
+// Filters shared by the search_images tool and the /api/images endpoint
+#[derive(Debug, Default, Deserialize, schemars::JsonSchema)]
+pub struct SearchQuery {
+    #[schemars(
+        description = "Words to look for in the prompts, all of them must appear. Matching ignores case."
+    )]
+    pub q: Option<String>,
+
+    #[schemars(
+        description = "Only images made with this model id, e.g. \"imagen-3.0-generate-002\"."
+    )]
+    pub model: Option<String>,
+
+    #[schemars(description = "Only images with this aspect ratio, e.g. \"16:9\".")]
+    pub aspect_ratio: Option<String>,
+
+    #[schemars(description = "Only images with this tag.")]
+    pub tag: Option<String>,
+
+    #[schemars(
+        description = "Only images created at or after this time, as a date such as \"2025-01-31\" or an RFC 3339 timestamp."
+    )]
+    pub created_from: Option<String>,
+
+    #[schemars(
+        description = "Only images created at or before this time, as a date such as \"2025-01-31\" (the whole day is included) or an RFC 3339 timestamp."
+    )]
+    pub created_to: Option<String>,
+
+    #[schemars(
+        description = "\"newest\" (the default), \"oldest\" or \"relevance\". Relevance needs q."
+    )]
+    pub sort: Option<SearchSort>,
+
+    #[schemars(description = "The number of images per page, up to 100. The default is 20.")]
+    pub limit: Option<usize>,
+
+    #[schemars(description = "The next_cursor of the previous page, to get the next page.")]
+    pub cursor: Option<String>,
+}
+
+// Where the next page starts: the sort key of the last image of the previous
+// page, together with the parameters of the search, so that images added in
+// the meantime neither repeat nor skip images. Sent to clients as base64 JSON.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Cursor {
+    sort: SearchSort,
+    // q, model, aspect_ratio, tag, created_from and created_to
+    filters: Vec<Option<String>>,
+    key: SortKey,
+}
+
+// What images are ordered by. Ties in the creation time are broken by id.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct SortKey {
+    score: usize,
+    // Nanoseconds since the epoch, None for entries without a valid timestamp
+    created_at: Option<i64>,
+    id: String,
+}
+
+impl SortKey {
+    // The order of two images in the results
+    fn order(&self, other: &Self, sort: SearchSort) -> Ordering {
+        let by_time = (self.created_at, &self.id).cmp(&(other.created_at, &other.id));
+        match sort {
+            SearchSort::Newest => by_time.reverse(),
+            SearchSort::Oldest => by_time,
+            SearchSort::Relevance => self.score.cmp(&other.score).then(by_time).reverse(),
+        }
+    }
+}
+
+impl Cursor {
+    fn encode(&self) -> String {
+        let json = serde_json::to_vec(self).unwrap_or_default();
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(json)
+    }
+
+    fn decode(cursor: &str) -> Option<Self> {
+        let json = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .decode(cursor)
+            .ok()?;
+        serde_json::from_slice(&json).ok()
+    }
+}
+
+impl SearchQuery {
+    fn filters(&self) -> Vec<Option<String>> {
+        vec![
+            self.q.clone(),
+            self.model.clone(),
+            self.aspect_ratio.clone(),
+            self.tag.clone(),
+            self.created_from.clone(),
+            self.created_to.clone(),
+        ]
+    }
+}
+
+// A gallery image in a search result
+#[derive(Debug, Serialize)]
+pub struct SearchResult {
+    pub url: String,
+    pub thumbnail_url: String,
+    #[serde(flatten)]
+    pub metadata: ImageMetadata,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SearchPage<T = SearchResult> {
+    // The number of matching images on all pages
+    pub total: usize,
+    pub images: Vec<T>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+}
+
+// Parameters of the /api/v1/images listing, a search without filters
+#[derive(Debug, Default, Deserialize)]
+pub struct ListQuery {
+    pub limit: Option<usize>,
+    pub cursor: Option<String>,
+    pub sort: Option<SearchSort>,
+}
+
+impl From<ListQuery> for SearchQuery {
+    fn from(query: ListQuery) -> Self {
+        SearchQuery {
+            limit: query.limit,
+            cursor: query.cursor,
+            sort: query.sort,
+            ..Default::default()
+        }
+    }
+}
+
+// A gallery image in the /api/v1/images listing
+#[derive(Debug, Serialize)]
+pub struct ImageSummary {
+    pub id: String,
+    pub filename: String,
+    pub url: String,
+    pub thumbnail_url: String,
+    // Missing when the file cannot be decoded
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub bytes: u64,
+    pub mime_type: String,
+    pub created_at: String,
+    pub prompt: Option<String>,
+}
+
+// Add the size and dimensions of the files to a page of results. Only the
+// image header is read, not the whole image.
+pub async fn summarize(page: SearchPage, images_dir: &Path) -> SearchPage<ImageSummary> {
+    let images_dir: PathBuf = images_dir.to_path_buf();
+    let SearchPage {
+        total,
+        images,
+        next_cursor,
+    } = page;
+    let summaries = tokio::task::spawn_blocking(move || {
+        images
+            .into_iter()
+            .map(|SearchResult {
+                      url,
+                      thumbnail_url,
+                      metadata,
+                  }| {
+                let path = images_dir.join(&metadata.filename);
+                let bytes = std::fs::metadata(&path)
+                    .map(|file| file.len())
+                    .unwrap_or_default();
+                let (width, height) = match image::image_dimensions(&path) {
+                    Ok((width, height)) => (Some(width), Some(height)),
+                    Err(e) => {
+                        warn!(filename = %metadata.filename, "Failed to read image dimensions: {}", e);
+                        (None, None)
+                    }
+                };
+                ImageSummary {
+                    id: metadata.id,
+                    filename: metadata.filename,
+                    url,
+                    thumbnail_url,
+                    width,
+                    height,
+                    bytes,
+                    mime_type: metadata.mime_type,
+                    created_at: metadata.created_at,
+                    prompt: metadata.prompt,
+                }
+            })
+            .collect()
+    })
+    .await
+    .unwrap_or_default();
+    SearchPage {
+        total,
+        images: summaries,
+        next_cursor,
+    }
+}
+
+// The metadata of every image in the images directory. Images without an
+// entry in the index, e.g. ones copied in by hand, only get what the file
+// system knows about them.
+pub async fn gallery_metadata(
+    metadata: &MetadataStore,
+    images_dir: &Path,
+) -> std::io::Result<Vec<ImageMetadata>> {
+    let mut indexed: HashMap<String, ImageMetadata> = metadata
+        .all()
+        .await?
+        .into_iter()
+        .map(|entry| (entry.filename.clone(), entry))
+        .collect();
+    let entries = resources::gallery_entries(images_dir).await?;
+    Ok(entries
+        .into_iter()
+        .map(|entry| {
+            indexed.remove(&entry.filename).unwrap_or_else(|| {
+                let created_at: DateTime<Local> = entry.modified.into();
+                ImageMetadata {
+                    id: entry.filename.clone(),
+                    mime_type: resources::mime_type_for(&entry.filename).to_string(),
+                    created_at: created_at.to_rfc3339(),
+                    filename: entry.filename,
+                    ..Default::default()
+                }
+            })
+        })
+        .collect())
+}
+
+// Parse a date or timestamp filter. A date stands for the start of that day,
+// or with `end_of_day` for its last moment.
+fn parse_time(value: &str, end_of_day: bool) -> Result<DateTime<FixedOffset>, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time);
+    }
+    let date = NaiveDate::parse_from_str(value, "%Y-%m-%d").map_err(|_| {
+        format!(
+            "Invalid date: {}, expected a date such as \"2025-01-31\" or an RFC 3339 timestamp",
+            value
+        )
+    })?;
+    let start = |date: NaiveDate| {
+        date.and_hms_opt(0, 0, 0)
+            .and_then(|time| time.and_local_timezone(Local).earliest())
+            .map(|time| time.fixed_offset())
+    };
+    let time = if end_of_day {
+        date.succ_opt()
+            .and_then(start)
+            .map(|time| time - chrono::Duration::nanoseconds(1))
+    } else {
+        start(date)
+    };
+    time.ok_or_else(|| format!("Invalid date: {}", value))
+}
+
+// Filter, sort and paginate gallery images. `base_url` is prepended to the
+// image paths to build their URLs.
+pub fn search(
+    entries: Vec<ImageMetadata>,
+    query: &SearchQuery,
+    base_url: &str,
+) -> Result<SearchPage, String> {
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT);
+    if !(1..=MAX_LIMIT).contains(&limit) {
+        return Err(format!(
+            "Invalid limit: {}, supported values are 1 to {}",
+            limit, MAX_LIMIT
+        ));
+    }
+    let sort = query.sort.unwrap_or_default();
+    let after = match &query.cursor {
+        Some(cursor) => {
+            let cursor =
+                Cursor::decode(cursor).ok_or_else(|| format!("Invalid cursor: {}", cursor))?;
+            if cursor.sort != sort || cursor.filters != query.filters() {
+                return Err(
+                    "Invalid cursor: it belongs to a search with other filters or another sort order, start again without a cursor"
+                        .to_string(),
+                );
+            }
+            Some(cursor.key)
+        }
+        None => None,
+    };
+    let created_from = query
+        .created_from
+        .as_deref()
+        .map(|value| parse_time(value, false))
+        .transpose()?;
+    let created_to = query
+        .created_to
+        .as_deref()
+        .map(|value| parse_time(value, true))
+        .transpose()?;
+    let terms = query
+        .q
+        .as_deref()
+        .unwrap_or_default()
+        .split_whitespace()
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>();
+    if sort == SearchSort::Relevance && terms.is_empty() {
+        return Err("Invalid parameters: sorting by relevance needs q".to_string());
+    }
+
+    let mut matches = Vec::new();
+    for entry in entries {
+        if let Some(model) = &query.model
+            && &entry.model != model
+        {
+            continue;
+        }
+        if let Some(aspect_ratio) = &query.aspect_ratio
+            && entry.aspect_ratio.as_ref() != Some(aspect_ratio)
+        {
+            continue;
+        }
+        if let Some(tag) = &query.tag
+            && !entry.tags.iter().any(|t| t.eq_ignore_ascii_case(tag))
+        {
+            continue;
+        }
+
+        let created_at = DateTime::parse_from_rfc3339(&entry.created_at).ok();
+        if let Some(from) = created_from
+            && created_at.is_none_or(|created_at| created_at < from)
+        {
+            continue;
+        }
+        if let Some(to) = created_to
+            && created_at.is_none_or(|created_at| created_at > to)
+        {
+            continue;
+        }
+
+        let mut score = 0;
+        if !terms.is_empty() {
+            let prompt = entry.prompt.as_deref().unwrap_or_default().to_lowercase();
+            let counts = terms
+                .iter()
+                .map(|term| prompt.matches(term.as_str()).count())
+                .collect::<Vec<_>>();
+            if counts.contains(&0) {
+                continue;
+            }
+            score = counts.iter().sum();
+        }
+        let key = SortKey {
+            score,
+            created_at: created_at.and_then(|created_at| created_at.timestamp_nanos_opt()),
+            id: entry.id.clone(),
+        };
+        matches.push((key, entry));
+    }
+
+    // Entries without a valid timestamp sort as the oldest
+    matches.sort_by(|(a, _), (b, _)| a.order(b, sort));
+
+    let total = matches.len();
+    let mut remaining = matches
+        .into_iter()
+        .filter(|(key, _)| {
+            after
+                .as_ref()
+                .is_none_or(|after| key.order(after, sort) == Ordering::Greater)
+        })
+        .peekable();
+    let page = remaining.by_ref().take(limit).collect::<Vec<_>>();
+    let next_cursor = match (page.last(), remaining.peek()) {
+        (Some((key, _)), Some(_)) => Some(
+            Cursor {
+                sort,
+                filters: query.filters(),
+                key: key.clone(),
+            }
+            .encode(),
+        ),
+        _ => None,
+    };
+    let images = page
+        .into_iter()
+        .map(|(_, metadata)| SearchResult {
+            url: format!("{}/images/{}", base_url, metadata.filename),
+            thumbnail_url: format!(
+                "{}{}",
+                base_url,
+                thumbnails::thumbnail_path(&metadata.filename)
+            ),
+            // Provider responses can be large, get_image_metadata has them
+            metadata: ImageMetadata {
+                provider_response: None,
+                ..metadata
+            },
+        })
+        .collect();
+    Ok(SearchPage {
+        total,
+        images,
+        next_cursor,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(id: &str, created_at: &str, prompt: &str) -> ImageMetadata {
+        ImageMetadata {
+            id: id.to_string(),
+            filename: format!("{}.png", id),
+            mime_type: "image/png".to_string(),
+            created_at: created_at.to_string(),
+            operation: "generate".to_string(),
+            prompt: Some(prompt.to_string()),
+            aspect_ratio: Some("1:1".to_string()),
+            provider: "imagen".to_string(),
+            model: "imagen-3.0-generate-002".to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn gallery() -> Vec<ImageMetadata> {
+        let mut cat = entry("a", "2025-01-01T10:00:00+00:00", "a cat on a cat tree");
+        cat.tags = vec!["Pets".to_string()];
+        let mut dog = entry("b", "2025-01-02T10:00:00+00:00", "a dog and a cat");
+        dog.aspect_ratio = Some("16:9".to_string());
+        let mut city = entry("c", "2025-01-03T10:00:00+00:00", "a city at night");
+        city.model = "imagen-4.0-generate-001".to_string();
+        vec![cat, dog, city]
+    }
+
+    fn ids(page: &SearchPage) -> Vec<&str> {
+        page.images
+            .iter()
+            .map(|image| image.metadata.id.as_str())
+            .collect()
+    }
+
+    fn run(query: &SearchQuery) -> SearchPage {
+        search(gallery(), query, "http://localhost").unwrap()
+    }
+
+    #[test]
+    fn sorts_newest_first_by_default() {
+        let page = run(&SearchQuery::default());
+        assert_eq!(ids(&page), ["c", "b", "a"]);
+        assert_eq!(page.total, 3);
+        assert_eq!(page.next_cursor, None);
+        assert_eq!(page.images[0].url, "http://localhost/images/c.png");
+
+        let page = run(&SearchQuery {
+            sort: Some(SearchSort::Oldest),
+            ..Default::default()
+        });
+        assert_eq!(ids(&page), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn sorts_by_relevance_then_newest() {
+        let page = run(&SearchQuery {
+            q: Some("CAT".to_string()),
+            sort: Some(SearchSort::Relevance),
+            ..Default::default()
+        });
+        assert_eq!(ids(&page), ["a", "b"]);
+
+        let page = run(&SearchQuery {
+            q: Some("t".to_string()),
+            sort: Some(SearchSort::Relevance),
+            ..Default::default()
+        });
+        // "a cat on a cat tree" and "a city at night" both contain 3 t's
+        assert_eq!(ids(&page), ["c", "a", "b"]);
+    }
+
+    #[test]
+    fn relevance_needs_a_query() {
+        let query = SearchQuery {
+            sort: Some(SearchSort::Relevance),
+            ..Default::default()
+        };
+        assert!(search(gallery(), &query, "").is_err());
+    }
+
+    #[test]
+    fn filters_by_terms_model_aspect_ratio_and_tag() {
+        let page = run(&SearchQuery {
+            q: Some("dog cat".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(ids(&page), ["b"]);
+
+        let page = run(&SearchQuery {
+            model: Some("imagen-4.0-generate-001".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(ids(&page), ["c"]);
+
+        let page = run(&SearchQuery {
+            aspect_ratio: Some("16:9".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(ids(&page), ["b"]);
+
+        let page = run(&SearchQuery {
+            tag: Some("pets".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(ids(&page), ["a"]);
+    }
+
+    #[test]
+    fn filters_by_creation_time() {
+        let page = run(&SearchQuery {
+            created_from: Some("2025-01-02T00:00:00+00:00".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(ids(&page), ["c", "b"]);
+
+        let page = run(&SearchQuery {
+            created_from: Some("2025-01-01T12:00:00+00:00".to_string()),
+            created_to: Some("2025-01-02T12:00:00+00:00".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(ids(&page), ["b"]);
+
+        let query = SearchQuery {
+            created_to: Some("yesterday".to_string()),
+            ..Default::default()
+        };
+        assert!(search(gallery(), &query, "").is_err());
+    }
+
+    #[test]
+    fn pages_stay_stable_across_an_insert() {
+        let mut query = SearchQuery {
+            limit: Some(2),
+            ..Default::default()
+        };
+        let first = run(&query);
+        assert_eq!(ids(&first), ["c", "b"]);
+        assert_eq!(first.total, 3);
+
+        // A new image arrives before the next page is fetched
+        let mut entries = gallery();
+        entries.push(entry("d", "2025-01-04T10:00:00+00:00", "a new image"));
+        query.cursor = first.next_cursor;
+        let second = search(entries, &query, "").unwrap();
+        assert_eq!(ids(&second), ["a"]);
+        assert_eq!(second.next_cursor, None);
+    }
+
+    #[test]
+    fn breaks_ties_in_the_creation_time_by_id() {
+        let entries = || {
+            ["x", "z", "y"]
+                .map(|id| entry(id, "2025-01-01T10:00:00+00:00", "same time"))
+                .to_vec()
+        };
+        let mut query = SearchQuery {
+            limit: Some(1),
+            ..Default::default()
+        };
+        let mut seen = Vec::new();
+        loop {
+            let page = search(entries(), &query, "").unwrap();
+            seen.extend(page.images.iter().map(|image| image.metadata.id.clone()));
+            match page.next_cursor {
+                Some(cursor) => query.cursor = Some(cursor),
+                None => break,
+            }
+        }
+        assert_eq!(seen, ["z", "y", "x"]);
+    }
+
+    #[test]
+    fn rejects_foreign_and_malformed_cursors() {
+        let query = SearchQuery {
+            limit: Some(1),
+            ..Default::default()
+        };
+        let cursor = run(&query).next_cursor;
+        assert!(cursor.is_some());
+
+        let other_sort = SearchQuery {
+            sort: Some(SearchSort::Oldest),
+            cursor: cursor.clone(),
+            ..Default::default()
+        };
+        assert!(search(gallery(), &other_sort, "").is_err());
+
+        let other_filter = SearchQuery {
+            model: Some("imagen-3.0-generate-002".to_string()),
+            cursor,
+            ..Default::default()
+        };
+        assert!(search(gallery(), &other_filter, "").is_err());
+
+        for cursor in ["2", "not a cursor"] {
+            let query = SearchQuery {
+                cursor: Some(cursor.to_string()),
+                ..Default::default()
+            };
+            assert!(search(gallery(), &query, "").is_err());
+        }
+    }
+
+    #[test]
+    fn parse_time_accepts_timestamps() {
+        let time = parse_time("2025-01-31T08:30:00+02:00", true).unwrap();
+        assert_eq!(time.to_rfc3339(), "2025-01-31T08:30:00+02:00");
+    }
+
+    #[test]
+    fn parse_time_expands_dates_to_the_whole_day() {
+        let start = parse_time("2025-01-31", false).unwrap();
+        let end = parse_time("2025-01-31", true).unwrap();
+        assert_eq!(start.naive_local().to_string(), "2025-01-31 00:00:00");
+        assert_eq!(
+            end.naive_local().to_string(),
+            "2025-01-31 23:59:59.999999999"
+        );
+    }
+
+    #[test]
+    fn parse_time_rejects_invalid_input() {
+        assert!(parse_time("2025-02-30", false).is_err());
+        assert!(parse_time("31.01.2025", false).is_err());
+        assert!(parse_time("", true).is_err());
+    }
+}
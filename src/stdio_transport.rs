@@ -0,0 +1,76 @@
+use futures::channel::mpsc;
+use futures::{Sink, SinkExt, Stream, StreamExt, stream};
+use rmcp::model::{ClientJsonRpcMessage, ServerJsonRpcMessage};
+use serde_json::Value;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tracing::{error, warn};
+
+use crate::progress::{self, ProgressTokens};
+
+// The stdio transport, one JSON-RPC message per line. rmcp has its own, this
+// one reads the messages itself to keep the progress tokens rmcp drops.
+pub fn transport(
+    progress_tokens: ProgressTokens,
+) -> (
+    impl Sink<ServerJsonRpcMessage, Error = std::io::Error>,
+    impl Stream<Item = ClientJsonRpcMessage>,
+) {
+    let (outgoing, mut outgoing_receiver) = mpsc::unbounded::<ServerJsonRpcMessage>();
+    tokio::spawn(async move {
+        let mut stdout = tokio::io::stdout();
+        while let Some(message) = outgoing_receiver.next().await {
+            let mut line = match serde_json::to_vec(&message) {
+                Ok(line) => line,
+                Err(e) => {
+                    error!("Failed to serialize MCP message: {}", e);
+                    continue;
+                }
+            };
+            line.push(b'\n');
+            if let Err(e) = async {
+                stdout.write_all(&line).await?;
+                stdout.flush().await
+            }
+            .await
+            {
+                error!("Failed to write MCP message: {}", e);
+                break;
+            }
+        }
+    });
+
+    let lines = BufReader::new(tokio::io::stdin()).lines();
+    let incoming = stream::unfold(lines, |mut lines| async move {
+        match lines.next_line().await {
+            Ok(Some(line)) => Some((line, lines)),
+            Ok(None) => None,
+            Err(e) => {
+                error!("Failed to read MCP message: {}", e);
+                None
+            }
+        }
+    })
+    .filter_map(move |line| {
+        let progress_tokens = progress_tokens.clone();
+        async move {
+            if line.trim().is_empty() {
+                return None;
+            }
+            let message = serde_json::from_str::<Value>(&line).and_then(|raw| {
+                if let Some(requested) = progress::requested_token(&raw) {
+                    progress_tokens.insert(requested);
+                }
+                serde_json::from_value::<ClientJsonRpcMessage>(raw)
+            });
+            match message {
+                Ok(message) => Some(message),
+                Err(e) => {
+                    warn!("Invalid MCP message: {}", e);
+                    None
+                }
+            }
+        }
+    });
+
+    (outgoing.sink_map_err(std::io::Error::other), incoming)
+}
@@ -0,0 +1,230 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use image::GenericImageView;
+use image::imageops::FilterType;
+use tracing::{info, warn};
+
+use crate::encoding::{self, OutputFormat};
+use crate::resources;
+
+// Variants larger than this on either side are refused, the original can be
+// fetched without parameters
+pub const MAX_VARIANT_DIMENSION: u32 = 2048;
+
+// Size of the thumbnails used by search results and the gallery
+pub const THUMBNAIL_DIMENSION: u32 = 256;
+
+// The query of /images/{filename}?w=256&h=256&format=webp. The image is
+// shrunk to fit into w and h, keeping its aspect ratio.
+#[derive(Debug, Default)]
+pub struct VariantQuery {
+    pub w: Option<u32>,
+    pub h: Option<u32>,
+    pub format: Option<OutputFormat>,
+}
+
+impl VariantQuery {
+    // Parse the query parameters, other parameters such as cache busters are
+    // ignored
+    pub fn from_params(params: &HashMap<String, String>) -> Result<Self, String> {
+        let dimension = |name: &str| {
+            params
+                .get(name)
+                .map(|value| {
+                    value
+                        .parse::<u32>()
+                        .map_err(|_| format!("Invalid {}: {}", name, value))
+                })
+                .transpose()
+        };
+        Ok(Self {
+            w: dimension("w")?,
+            h: dimension("h")?,
+            format: params
+                .get("format")
+                .map(|value| value.parse())
+                .transpose()?,
+        })
+    }
+
+    // Without parameters the original file is served as it is
+    pub fn is_empty(&self) -> bool {
+        self.w.is_none() && self.h.is_none() && self.format.is_none()
+    }
+}
+
+#[derive(Debug)]
+pub enum VariantError {
+    NotFound,
+    Invalid(String),
+    Failed(String),
+}
+
+impl std::fmt::Display for VariantError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VariantError::NotFound => write!(f, "Image not found"),
+            VariantError::Invalid(message) | VariantError::Failed(message) => {
+                write!(f, "{}", message)
+            }
+        }
+    }
+}
+
+pub struct Variant {
+    pub bytes: Vec<u8>,
+    pub mime_type: &'static str,
+}
+
+impl Variant {
+    fn new(bytes: Vec<u8>, format: OutputFormat) -> Self {
+        Self {
+            bytes,
+            mime_type: format.mime_type(),
+        }
+    }
+}
+
+// The URL path of a thumbnail of a gallery image
+pub fn thumbnail_path(filename: &str) -> String {
+    format!("/images/{}?w={}&format=webp", filename, THUMBNAIL_DIMENSION)
+}
+
+// Where a variant is cached: resources_path/cache/{filename}.{w}x{h}.{ext},
+// with 0 for a side without a limit
+fn cache_path(
+    resources_path: &Path,
+    filename: &str,
+    w: u32,
+    h: u32,
+    format: OutputFormat,
+) -> PathBuf {
+    resources_path
+        .join("cache")
+        .join(format!("{}.{}x{}.{}", filename, w, h, format.as_str()))
+}
+
+// Delete the cached variants of a gallery image, e.g. after the image was
+// removed. Returns the number of deleted files.
+pub async fn remove_cached_variants(
+    resources_path: &Path,
+    filename: &str,
+) -> std::io::Result<usize> {
+    let prefix = format!("{}.", filename);
+    let mut removed = 0;
+    let mut dir = match tokio::fs::read_dir(resources_path.join("cache")).await {
+        Ok(dir) => dir,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    while let Some(entry) = dir.next_entry().await? {
+        let name = entry.file_name();
+        let Some(variant) = name.to_str().and_then(|name| name.strip_prefix(&prefix)) else {
+            continue;
+        };
+        // Only {w}x{h}.{ext}, other images can share the prefix
+        let is_variant = variant.split_once('.').is_some_and(|(size, _)| {
+            size.split_once('x').is_some_and(|(w, h)| {
+                [w, h]
+                    .iter()
+                    .all(|side| !side.is_empty() && side.bytes().all(|b| b.is_ascii_digit()))
+            })
+        });
+        if is_variant {
+            tokio::fs::remove_file(entry.path()).await?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+fn resize(bytes: &[u8], w: u32, h: u32, format: OutputFormat) -> Result<Vec<u8>, String> {
+    let decoded = image::load_from_memory(bytes).map_err(|e| e.to_string())?;
+    let (width, height) = decoded.dimensions();
+    let max_width = if w == 0 { width } else { w.min(width) };
+    let max_height = if h == 0 { height } else { h.min(height) };
+    // Variants are never larger than the original
+    let resized = if max_width < width || max_height < height {
+        decoded.resize(max_width, max_height, FilterType::Lanczos3)
+    } else {
+        decoded
+    };
+    encoding::encode(&resized, format, None)
+}
+
+// A resized or converted version of a gallery image, from the cache when
+// it is newer than the image
+pub async fn variant(
+    resources_path: &Path,
+    filename: &str,
+    query: &VariantQuery,
+) -> Result<Variant, VariantError> {
+    if !resources::is_safe_filename(filename) {
+        return Err(VariantError::NotFound);
+    }
+    let (w, h) = (query.w.unwrap_or(0), query.h.unwrap_or(0));
+    for (name, value) in [("w", query.w), ("h", query.h)] {
+        if let Some(value) = value
+            && !(1..=MAX_VARIANT_DIMENSION).contains(&value)
+        {
+            return Err(VariantError::Invalid(format!(
+                "Invalid {}: {}, supported values are 1 to {}",
+                name, value, MAX_VARIANT_DIMENSION
+            )));
+        }
+    }
+    let source_format = OutputFormat::for_mime_type(resources::mime_type_for(filename));
+    let format = query.format.or(source_format).unwrap_or(OutputFormat::Png);
+
+    let source = resources_path.join("images").join(filename);
+    let modified = match tokio::fs::metadata(&source).await {
+        Ok(metadata) if metadata.is_file() => metadata.modified().ok(),
+        Ok(_) => return Err(VariantError::NotFound),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Err(VariantError::NotFound),
+        Err(e) => return Err(VariantError::Failed(format!("Failed to read image: {}", e))),
+    };
+
+    let cached = cache_path(resources_path, filename, w, h, format);
+    if let Ok(metadata) = tokio::fs::metadata(&cached).await
+        && metadata.modified().ok() >= modified
+        && let Ok(bytes) = tokio::fs::read(&cached).await
+    {
+        return Ok(Variant::new(bytes, format));
+    }
+
+    let bytes = tokio::fs::read(&source)
+        .await
+        .map_err(|e| VariantError::Failed(format!("Failed to read image: {}", e)))?;
+    // AVIF can be written but not decoded here, browsers and most clients
+    // show the original just as well
+    if source_format == Some(OutputFormat::Avif) {
+        return Ok(Variant::new(bytes, OutputFormat::Avif));
+    }
+    // Resizing large images takes a while, keep it off the async workers
+    let bytes = tokio::task::spawn_blocking(move || resize(&bytes, w, h, format))
+        .await
+        .map_err(|e| VariantError::Failed(e.to_string()))?
+        .map_err(|e| VariantError::Failed(format!("Failed to resize image: {}", e)))?;
+
+    // A failed cache write only costs another resize next time. The variant
+    // is written to a temporary file first so that readers never see half of it.
+    let temporary =
+        cached.with_extension(format!("{}.tmp-{}", format.as_str(), nanoid::nanoid!(6)));
+    let write = async {
+        if let Some(dir) = cached.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        tokio::fs::write(&temporary, &bytes).await?;
+        tokio::fs::rename(&temporary, &cached).await
+    };
+    match write.await {
+        Ok(()) => info!(path = %cached.display(), "Cached image variant."),
+        Err(e) => {
+            warn!(path = %cached.display(), "Failed to cache image variant: {}", e);
+            let _ = tokio::fs::remove_file(&temporary).await;
+        }
+    }
+
+    Ok(Variant::new(bytes, format))
+}